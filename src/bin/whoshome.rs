@@ -1,80 +1,1450 @@
-use anyhow::{Context, Result};
-use clap::{Parser, Subcommand};
+use std::{
+    borrow::Borrow,
+    io::IsTerminal,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
 use config::Config;
-use router::{Client, Router};
-use tracing::trace;
+use owo_colors::OwoColorize;
+use router::{normalize_mac, Client, Router, RouterError, RouterKind};
+use tracing::{debug, info, trace, warn};
 use tracing_subscriber::{prelude::*, util::SubscriberInitExt};
 
-use whoshome::{config, router, unifi_dream_router};
+use whoshome::{
+    changes::{changes, Change, Summary},
+    clients::{
+        client_from_mac, find_device, find_in, is_not_found, known_and_online, known_clients,
+        online_clients, KnownClients,
+    },
+    config, credentials,
+    history::PresenceHistory,
+    metrics::{self, PresenceMetrics},
+    mqtt::MqttPublisher,
+    presence::{
+        device_matches, is_home, owners, persons_home, statuses, unassigned, Debounce,
+        PersonStatus, WhosHome,
+    },
+    router, schedule,
+    seen::SeenClients,
+    server::{self, Polled, SharedStatuses},
+    systemd,
+    webhook::Webhooks,
+};
+
+const DEFAULT_COMMAND_DELAY_MS: u64 = 250;
 
 #[derive(clap::Parser)]
 struct Cli {
+    /// Increase logging verbosity (-v info, -vv debug, -vvv trace)
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+    /// Also append log output to this file
+    #[arg(long, global = true)]
+    log_file: Option<PathBuf>,
+    /// Don't print a confirmation when a command succeeds, and only print
+    /// the summary line of show-whos-home
+    #[arg(short, long, global = true)]
+    quiet: bool,
+    /// Resolve clients for block/unblock but don't send any commands
+    #[arg(long, global = true)]
+    dry_run: bool,
+    /// Path to the config file (defaults to config.toml or config.dhall in
+    /// ~/.config/whoshome or the current directory)
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    /// Router password (discouraged, prefer the environment, keyring or .netrc)
+    #[arg(long, global = true)]
+    password: Option<String>,
+    /// Talk to this router instead of the ones in the config
+    #[arg(long, global = true, value_name = "HOST")]
+    router: Option<String>,
+    /// Never color the output (also set by NO_COLOR)
+    #[arg(long, global = true)]
+    no_color: bool,
+    /// Override the kind of router from the config, e.g. `mock` to try the tool out
+    #[arg(long, global = true, value_enum)]
+    router_kind: Option<RouterKind>,
+    /// Give up on requests to the router after this many seconds
+    #[arg(long, global = true, value_name = "SECS")]
+    timeout_secs: Option<u64>,
     #[command(subcommand)]
     command: Commands,
 }
 
 #[derive(Subcommand)]
 enum Commands {
-    Block { client_name: String },
-    Unblock { client_name: String },
-    ShowWhosHome,
+    Block {
+        #[arg(required_unless_present = "mac")]
+        client_names: Vec<String>,
+        /// Block this MAC address without looking up the client by name
+        #[arg(long)]
+        mac: Vec<String>,
+    },
+    Unblock {
+        #[arg(required_unless_present = "mac")]
+        client_names: Vec<String>,
+        /// Unblock this MAC address without looking up the client by name
+        #[arg(long)]
+        mac: Vec<String>,
+    },
+    /// Block every device of a person in the config
+    BlockPerson {
+        person_name: String,
+    },
+    /// Unblock every device of a person in the config
+    UnblockPerson {
+        person_name: String,
+    },
+    /// Unblock every client that is currently blocked
+    UnblockAll,
+    /// Block and unblock clients according to the schedule in the config,
+    /// meant to be run every few minutes
+    Enforce,
+    /// Block the client if it isn't blocked, otherwise unblock it
+    Toggle {
+        client_name: String,
+    },
+    ShowWhosHome(ShowArgs),
+    /// List everybody in the config, alphabetically, as home or away
+    Roster,
+    /// Poll the routers and report arrivals and departures
+    Watch {
+        #[arg(long, default_value_t = 60)]
+        interval_secs: u64,
+        /// Show a desktop notification for each arrival and departure
+        #[arg(long)]
+        notify: bool,
+        /// POST each arrival and departure as JSON to this URL, in addition
+        /// to the webhooks in the config
+        #[arg(long)]
+        webhook_url: Vec<String>,
+        /// Give up when the routers have been unreachable for this many
+        /// polls in a row, instead of waiting for them forever
+        #[arg(long, value_name = "POLLS")]
+        max_unreachable: Option<u32>,
+    },
+    /// Serve presence as Prometheus metrics on /metrics
+    Metrics {
+        #[arg(long, default_value = "127.0.0.1:9393")]
+        listen: SocketAddr,
+        #[arg(long, default_value_t = 60)]
+        interval_secs: u64,
+    },
+    /// Serve presence as JSON on /whoshome, polling the routers in the background
+    Serve {
+        #[arg(long, default_value_t = 8080)]
+        port: u16,
+        #[arg(long, default_value = "127.0.0.1")]
+        bind: IpAddr,
+        #[arg(long, default_value_t = 60)]
+        interval_secs: u64,
+    },
+    /// Publish presence to the MQTT broker from the config, e.g. for Home Assistant
+    Mqtt {
+        #[arg(long, default_value_t = 60)]
+        interval_secs: u64,
+    },
+    /// List the clients known by the routers
+    ListClients {
+        /// Only list the clients that are currently online
+        #[arg(long)]
+        online_only: bool,
+        #[command(flatten)]
+        sort: SortArgs,
+    },
+    /// Print the number of online clients, for status bars and scripts
+    Count {
+        /// Count the people home instead
+        #[arg(long)]
+        persons: bool,
+    },
+    /// List the clients the routers currently block
+    ListBlocked(SortArgs),
+    /// List every known client with whether it is online and blocked
+    Overview(SortArgs),
+    /// List the online clients that aren't any person's device
+    Guests(SortArgs),
+    /// Show connection details for a client
+    Status {
+        client_name: String,
+    },
+    /// Show which person in the config owns a device name or MAC
+    WhoOwns {
+        device_or_mac: String,
+    },
+    /// Print a completion script for the shell to stdout
+    Completions {
+        shell: Shell,
+    },
+    /// Print the Dhall type of the config with an example, to start a
+    /// config.dhall from
+    Schema,
+    /// Check that logging in to the routers works
+    Login {
+        /// Ask for the password and save it in the system keyring first
+        #[arg(long)]
+        store: bool,
+    },
+    /// Check the config against the routers, e.g. for misspelled devices
+    Doctor,
+    /// Exit with 0 if the person is home, 1 if away and 2 on errors
+    IsHome {
+        person: String,
+    },
 }
 
-async fn find_client(router: &dyn Router, client_name: &str) -> Result<Client> {
-    let clients = router.known_clients().await?;
-    let client = clients
-        .iter()
-        .find(|c| c.name == client_name)
-        .with_context(|| format!("Could not find client named {client_name}"))?;
-    Ok(client.clone())
+#[derive(clap::Args)]
+struct ShowArgs {
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    format: OutputFormat,
+    /// Also list the people who are away
+    #[arg(long)]
+    show_away: bool,
+    /// Ignore wired clients, which tend to stay online when their owner is out
+    #[arg(long)]
+    wireless_only: bool,
+    /// Print this for each person instead, with {name}, {status} (home or
+    /// away) and {duration} replaced, e.g. "{name}: {status}"
+    #[arg(long, conflicts_with = "format")]
+    template: Option<String>,
+    /// Show the MAC of the device each person was seen home with
+    #[arg(long)]
+    show_mac: bool,
+    /// Show the last recorded presence, marked as stale, if the routers
+    /// can't be reached
+    #[arg(long)]
+    allow_stale: bool,
+}
+
+#[derive(clap::Args)]
+struct SortArgs {
+    #[arg(long, value_enum, default_value_t = SortKey::Name)]
+    sort: SortKey,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum SortKey {
+    Name,
+    Mac,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
+#[derive(Clone, Copy)]
+struct RunOptions {
+    quiet: bool,
+    dry_run: bool,
+    /// Pause between block/unblock commands so the router isn't flooded.
+    command_delay: Duration,
+}
+
+#[derive(Clone, Copy)]
+enum Action {
+    Block,
+    Unblock,
+}
+
+impl Action {
+    fn verb(self) -> &'static str {
+        match self {
+            Action::Block => "block",
+            Action::Unblock => "unblock",
+        }
+    }
+
+    fn past_tense(self) -> &'static str {
+        match self {
+            Action::Block => "Blocked",
+            Action::Unblock => "Unblocked",
+        }
+    }
+}
+
+async fn apply(
+    routers: &[Box<dyn Router>],
+    action: Action,
+    client_names: &[String],
+    macs: &[String],
+    run_options: RunOptions,
+) -> Result<()> {
+    let known = KnownClients::fetch(routers).await?;
+    let mut clients = Vec::new();
+    let mut failed = 0;
+    for client_name in client_names {
+        match find_in(known.all(), client_name) {
+            Ok(client) => clients.push(client),
+            Err(e) => {
+                eprintln!("{e:#}");
+                failed += 1;
+            }
+        }
+    }
+    for mac in macs {
+        match client_from_mac(mac) {
+            Ok(client) => clients.push(client),
+            Err(e) => {
+                eprintln!("{e:#}");
+                failed += 1;
+            }
+        }
+    }
+
+    failed += perform(action, known.on_routers(clients), run_options).await;
+    if failed > 0 {
+        bail!("{failed} client(s) failed");
+    }
+    Ok(())
+}
+
+/// Blocks or unblocks each client on the router given with it, returning how
+/// many failed.
+async fn perform(
+    action: Action,
+    clients: Vec<(&dyn Router, Client)>,
+    run_options: RunOptions,
+) -> usize {
+    let mut failed = 0;
+    for (i, (router, client)) in clients.into_iter().enumerate() {
+        if run_options.dry_run {
+            println!(
+                "[dry-run] Would {} {} ({})",
+                action.verb(),
+                client.name,
+                client.mac
+            );
+            continue;
+        }
+        if i > 0 {
+            tokio::time::sleep(run_options.command_delay).await;
+        }
+        let result = match action {
+            Action::Block => router.block_client(&client).await,
+            Action::Unblock => router.unblock_client(&client).await,
+        };
+        match result {
+            Ok(()) => confirm(run_options.quiet, action.past_tense(), &client),
+            Err(e) => {
+                eprintln!("Failed on {}: {e:#}", client.name);
+                failed += 1;
+            }
+        }
+    }
+    failed
+}
+
+/// Devices the router doesn't know are skipped rather than failing the
+/// whole person.
+async fn apply_to_person(
+    routers: &[Box<dyn Router>],
+    config: &Config,
+    action: Action,
+    person_name: &str,
+    run_options: RunOptions,
+) -> Result<()> {
+    let person = config.person(person_name)?;
+    let known = KnownClients::fetch(routers).await?;
+    let mut clients = Vec::new();
+    for device in &person.devices {
+        match find_device(known.all(), device) {
+            Ok(client) => clients.push(client),
+            Err(e) => eprintln!("Skipped {}: {e:#}", device.name()),
+        }
+    }
+    let count = clients.len();
+    let failed = perform(action, known.on_routers(clients), run_options).await;
+    if failed > 0 {
+        bail!("{failed} of {count} device(s) failed");
+    }
+    Ok(())
+}
+
+async fn toggle(
+    routers: &[Box<dyn Router>],
+    client_name: &str,
+    run_options: RunOptions,
+) -> Result<()> {
+    let known = KnownClients::fetch(routers).await?;
+    let client = find_in(known.all(), client_name)?;
+    let action = if client.blocked {
+        Action::Unblock
+    } else {
+        Action::Block
+    };
+    if perform(action, known.on_routers(vec![client]), run_options).await > 0 {
+        bail!("Failed to {} {client_name}", action.verb());
+    }
+    Ok(())
+}
+
+/// Only clients whose block state differs from what the schedule wants are
+/// touched, so running this often doesn't keep re-blocking them.
+async fn enforce(
+    routers: &[Box<dyn Router>],
+    config: &Config,
+    run_options: RunOptions,
+) -> Result<()> {
+    let now = chrono::Local::now().time();
+    let known = KnownClients::fetch(routers).await?;
+    let (mut to_block, mut to_unblock) = (Vec::new(), Vec::new());
+    for client in known.all() {
+        match schedule::should_block(config, client, now) {
+            Some(true) if !client.blocked => to_block.push(client.clone()),
+            Some(false) if client.blocked => to_unblock.push(client.clone()),
+            _ => {}
+        }
+    }
+    let failed = perform(Action::Block, known.on_routers(to_block), run_options).await
+        + perform(Action::Unblock, known.on_routers(to_unblock), run_options).await;
+    if failed > 0 {
+        bail!("{failed} client(s) failed");
+    }
+    Ok(())
+}
+
+async fn unblock_all(routers: &[Box<dyn Router>], run_options: RunOptions) -> Result<()> {
+    let known = KnownClients::fetch(routers).await?;
+    // Every router's own list, as a client blocked on several routers has
+    // to be unblocked on each of them
+    let blocked: Vec<_> = known
+        .by_router()
+        .filter(|(_, c)| c.blocked)
+        .map(|(router, c)| (router, c.clone()))
+        .collect();
+    let count = blocked.len();
+    let failed = perform(Action::Unblock, blocked, run_options).await;
+    if failed > 0 {
+        bail!("{failed} of {count} client(s) failed");
+    }
+    if !run_options.quiet && !run_options.dry_run {
+        println!("Unblocked {count} client(s)");
+    }
+    Ok(())
 }
 
 #[tokio::main]
-async fn main() -> Result<()> {
-    configure_tracing();
+async fn main() -> ExitCode {
+    match run().await {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("Error: {e:?}");
+            ExitCode::from(2)
+        }
+    }
+}
+
+async fn run() -> Result<ExitCode> {
     let options = Cli::parse();
-    let config = config::get_config().context("Failed to read settings")?;
-    let router = unifi_dream_router::UnifiDreamRouter::new(&config.router)
+    configure_tracing(options.verbose, options.log_file.as_deref())?;
+    if let Commands::Completions { shell } = options.command {
+        let mut command = Cli::command();
+        let name = command.get_name().to_string();
+        clap_complete::generate(shell, &mut command, name, &mut std::io::stdout());
+        return Ok(ExitCode::SUCCESS);
+    }
+    if let Commands::Schema = options.command {
+        print!("{}", config::DHALL_SCHEMA);
+        return Ok(ExitCode::SUCCESS);
+    }
+    let mut config =
+        config::get_config(options.config.as_deref()).context("Failed to read settings")?;
+    if let Some(router) = options.router.clone() {
+        config.router = router;
+        config.routers.clear();
+    }
+    if let Some(timeout_secs) = options.timeout_secs {
+        config.timeout_secs = Some(timeout_secs);
+    }
+    let credential_order = config
+        .credential_order
+        .as_deref()
+        .unwrap_or(&credentials::DEFAULT_CREDENTIAL_ORDER);
+    let credentials = Arc::new(credentials::CredentialChain::new(
+        credential_order,
+        options.password.clone(),
+        credentials::netrc_path(config.netrc.as_deref())?,
+    ));
+    let router_kind = options.router_kind.unwrap_or(config.router_kind);
+    let routers = router::create_routers(&config, router_kind, credentials)
         .context("Failed to create router interface")?;
 
+    let run_options = RunOptions {
+        quiet: options.quiet,
+        dry_run: options.dry_run,
+        command_delay: Duration::from_millis(
+            config.command_delay_ms.unwrap_or(DEFAULT_COMMAND_DELAY_MS),
+        ),
+    };
     match options.command {
-        Commands::Block { client_name } => {
-            router
-                .block_client(&find_client(&router, &client_name).await?)
-                .await?
+        Commands::Block { client_names, mac } => {
+            apply(&routers, Action::Block, &client_names, &mac, run_options).await?
+        }
+        Commands::Unblock { client_names, mac } => {
+            apply(&routers, Action::Unblock, &client_names, &mac, run_options).await?
+        }
+        Commands::BlockPerson { person_name } => {
+            apply_to_person(&routers, &config, Action::Block, &person_name, run_options).await?
+        }
+        Commands::UnblockPerson { person_name } => {
+            apply_to_person(
+                &routers,
+                &config,
+                Action::Unblock,
+                &person_name,
+                run_options,
+            )
+            .await?
+        }
+        Commands::UnblockAll => unblock_all(&routers, run_options).await?,
+        Commands::Enforce => enforce(&routers, &config, run_options).await?,
+        Commands::Toggle { client_name } => toggle(&routers, &client_name, run_options).await?,
+        Commands::ShowWhosHome(args) => {
+            let color = use_color(options.no_color);
+            show_who_is_home(&routers, &config, &args, color, options.quiet).await?
+        }
+        Commands::Roster => roster(&routers, &config).await?,
+        Commands::Watch {
+            interval_secs,
+            notify,
+            webhook_url,
+            max_unreachable,
+        } => {
+            let urls: Vec<_> = config.webhooks.iter().cloned().chain(webhook_url).collect();
+            let webhooks = if urls.is_empty() {
+                None
+            } else {
+                Some(Webhooks::new(&config, urls)?)
+            };
+            watch(
+                &routers,
+                &config,
+                Duration::from_secs(interval_secs),
+                notify,
+                webhooks.as_ref(),
+                max_unreachable,
+            )
+            .await?
+        }
+        Commands::Metrics {
+            listen,
+            interval_secs,
+        } => {
+            serve_metrics(
+                &routers,
+                &config,
+                listen,
+                Duration::from_secs(interval_secs),
+            )
+            .await?
+        }
+        Commands::Serve {
+            port,
+            bind,
+            interval_secs,
+        } => {
+            serve(
+                &routers,
+                &config,
+                SocketAddr::new(bind, port),
+                Duration::from_secs(interval_secs),
+            )
+            .await?
+        }
+        Commands::Mqtt { interval_secs } => {
+            publish_mqtt(&routers, &config, Duration::from_secs(interval_secs)).await?
+        }
+        Commands::ListClients { online_only, sort } => {
+            list_clients(&routers, online_only, sort.sort).await?
+        }
+        Commands::Count { persons } => count(&routers, &config, persons).await?,
+        Commands::ListBlocked(sort) => list_blocked(&routers, sort.sort).await?,
+        Commands::Overview(sort) => overview(&routers, sort.sort).await?,
+        Commands::Guests(sort) => guests(&routers, &config, sort.sort).await?,
+        Commands::Status { client_name } => status(&routers, &client_name).await?,
+        Commands::WhoOwns { device_or_mac } => who_owns(&config, &device_or_mac),
+        Commands::Completions { .. } | Commands::Schema => {
+            unreachable!("handled before reading the config")
+        }
+        Commands::Login { store } => {
+            if store {
+                store_password(&routers, &config)?;
+            }
+            login(&routers, &config).await?
+        }
+        Commands::Doctor => doctor(&routers, &config).await?,
+        Commands::IsHome { person } => {
+            return is_home_exit_code(&routers, &config, &person, options.verbose > 0).await
+        }
+    };
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn confirm(quiet: bool, action: &str, client: &Client) {
+    if let Some(confirmation) = confirmation(quiet, action, client) {
+        println!("{confirmation}");
+    }
+}
+
+/// What [`confirm`] prints, nothing with `--quiet`.
+fn confirmation(quiet: bool, action: &str, client: &Client) -> Option<String> {
+    (!quiet).then(|| format!("{action} {} ({})", client.name, client.mac))
+}
+
+async fn list_clients(routers: &[Box<dyn Router>], online_only: bool, sort: SortKey) -> Result<()> {
+    let mut clients = if online_only {
+        online_clients(routers)
+            .await
+            .context("Failed to get list of connected client")?
+    } else {
+        known_clients(routers)
+            .await
+            .context("Failed to get list of known clients")?
+    };
+    sort_clients(&mut clients, sort);
+    let mut headers = vec!["NAME", "MAC", "VENDOR", "IP", "LAST SEEN"];
+    if online_only {
+        headers.push("SIGNAL");
+    }
+    let rows = clients
+        .into_iter()
+        .map(|c| {
+            let signal = match (c.wired, c.signal) {
+                (Some(true), _) => "wired".to_string(),
+                (_, Some(signal)) => format!("{signal} dBm"),
+                (_, None) => String::new(),
+            };
+            let mut row = vec![
+                c.name,
+                c.mac,
+                c.vendor.unwrap_or_default(),
+                c.ip.unwrap_or_default(),
+                c.last_seen.map(format_last_seen).unwrap_or_default(),
+            ];
+            if online_only {
+                row.push(signal);
+            }
+            row
+        })
+        .collect();
+    print_table(&headers, rows);
+    Ok(())
+}
+
+async fn count(routers: &[Box<dyn Router>], config: &Config, persons: bool) -> Result<()> {
+    let clients = online_clients(routers)
+        .await
+        .context("Failed to get list of connected client")?;
+    if persons {
+        println!("{}", persons_home(config, &clients).len());
+    } else {
+        println!("{}", clients.len());
+    }
+    Ok(())
+}
+
+async fn list_blocked(routers: &[Box<dyn Router>], sort: SortKey) -> Result<()> {
+    let mut clients = known_clients(routers)
+        .await
+        .context("Failed to get list of known clients")?;
+    sort_clients(&mut clients, sort);
+    let rows = clients
+        .into_iter()
+        .filter(|c| c.blocked)
+        .map(|c| vec![c.name, c.mac])
+        .collect();
+    print_table(&["NAME", "MAC"], rows);
+    Ok(())
+}
+
+async fn overview(routers: &[Box<dyn Router>], sort: SortKey) -> Result<()> {
+    let (mut known, online) = known_and_online(routers).await?;
+    sort_clients(&mut known, sort);
+    let yes_no = |b: bool| if b { "yes" } else { "no" }.to_string();
+    let rows = known
+        .into_iter()
+        .map(|c| {
+            let is_online = online.iter().any(|o| o.mac == c.mac);
+            vec![c.name, c.mac, yes_no(is_online), yes_no(c.blocked)]
+        })
+        .collect();
+    print_table(&["NAME", "MAC", "ONLINE", "BLOCKED"], rows);
+    Ok(())
+}
+
+async fn guests(routers: &[Box<dyn Router>], config: &Config, sort: SortKey) -> Result<()> {
+    let clients = online_clients(routers)
+        .await
+        .context("Failed to get list of connected client")?;
+    let mut guests = unassigned(config, &clients);
+    sort_clients(&mut guests, sort);
+    let rows = guests
+        .into_iter()
+        .map(|c| {
+            vec![
+                c.name.clone(),
+                c.mac.clone(),
+                c.vendor.clone().unwrap_or_default(),
+                c.ip.clone().unwrap_or_default(),
+            ]
+        })
+        .collect();
+    print_table(&["NAME", "MAC", "VENDOR", "IP"], rows);
+    Ok(())
+}
+
+/// Sorts for display only, so the order doesn't jump around between runs;
+/// the routers' own order is kept everywhere else.
+fn sort_clients<C: Borrow<Client>>(clients: &mut [C], sort: SortKey) {
+    let name = |c: &Client| c.name.to_lowercase();
+    let mac = |c: &Client| normalize_mac(&c.mac);
+    clients.sort_by_cached_key(|c| {
+        let c = c.borrow();
+        match sort {
+            SortKey::Name => (name(c), mac(c)),
+            SortKey::Mac => (mac(c), name(c)),
+        }
+    });
+}
+
+fn format_last_seen(timestamp: i64) -> String {
+    let ago = chrono::Utc::now().timestamp() - timestamp;
+    if ago < 60 {
+        "just now".to_string()
+    } else {
+        format!("{} ago", format_duration(ago as u64))
+    }
+}
+
+fn print_table(headers: &[&str], rows: Vec<Vec<String>>) {
+    let mut widths: Vec<_> = headers.iter().map(|h| h.chars().count()).collect();
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let format_row = |cells: Vec<&str>| {
+        cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{cell:width$}"))
+            .collect::<Vec<_>>()
+            .join("  ")
+            .trim_end()
+            .to_string()
+    };
+    println!("{}", format_row(headers.to_vec()));
+    for row in &rows {
+        println!("{}", format_row(row.iter().map(String::as_str).collect()));
+    }
+}
+
+async fn status(routers: &[Box<dyn Router>], client_name: &str) -> Result<()> {
+    let (known, online) = known_and_online(routers).await?;
+    let (client, is_online) = match find_in(&online, client_name) {
+        Ok(client) => (client, true),
+        Err(e) if !is_not_found(&e) => return Err(e),
+        Err(_) => (find_in(&known, client_name)?, false),
+    };
+    let unknown = || "unknown".to_string();
+    println!("Name:       {}", client.name);
+    println!("MAC:        {}", client.mac);
+    println!(
+        "Status:     {}",
+        if is_online { "online" } else { "offline" }
+    );
+    println!("IP:         {}", client.ip.unwrap_or_else(unknown));
+    println!(
+        "Uptime:     {}",
+        client.uptime.map(format_duration).unwrap_or_else(unknown)
+    );
+    println!(
+        "Connection: {}",
+        match client.wired {
+            Some(true) => "wired".to_string(),
+            Some(false) => "wireless".to_string(),
+            None => unknown(),
+        }
+    );
+    Ok(())
+}
+
+fn format_duration(secs: u64) -> String {
+    let (days, hours, minutes) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60);
+    match (days, hours) {
+        (0, 0) => format!("{minutes}m"),
+        (0, _) => format!("{hours}h {minutes}m"),
+        _ => format!("{days}d {hours}h"),
+    }
+}
+
+async fn roster(routers: &[Box<dyn Router>], config: &Config) -> Result<()> {
+    let clients = online_clients(routers)
+        .await
+        .context("Failed to get list of connected client")?;
+    let mut persons: Vec<_> = config.persons.iter().collect();
+    persons.sort_by(|a, b| a.name.cmp(&b.name));
+    let rows = persons
+        .into_iter()
+        .map(|p| {
+            let (mark, status) = if is_home(p, &clients) {
+                ("✓", "home")
+            } else {
+                ("✗", "away")
+            };
+            vec![mark.to_string(), p.name.clone(), status.to_string()]
+        })
+        .collect();
+    print_table(&["", "NAME", "STATUS"], rows);
+    Ok(())
+}
+
+async fn show_who_is_home(
+    routers: &[Box<dyn Router>],
+    config: &Config,
+    args: &ShowArgs,
+    color: bool,
+    quiet: bool,
+) -> Result<()> {
+    let mut history = load_history();
+    let (statuses, stale_as_of) = match online_clients(routers).await {
+        Ok(mut clients) => {
+            trace!("Online clients {clients:?}");
+            if args.wireless_only {
+                clients.retain(|c| c.wired != Some(true));
+            }
+            let mut statuses = statuses(config, &clients);
+            record_history(&mut history, &mut statuses);
+            (statuses, None)
         }
-        Commands::Unblock { client_name } => {
-            router
-                .unblock_client(&find_client(&router, &client_name).await?)
-                .await?
+        Err(e) if args.allow_stale => {
+            let Some((statuses, recorded_at)) = last_known_statuses(config, history.as_ref())
+            else {
+                return Err(e.context(
+                    "Failed to get list of connected client, and no presence was recorded before",
+                ));
+            };
+            eprintln!("Warning: {e}");
+            (statuses, Some(recorded_at))
         }
-        Commands::ShowWhosHome => show_who_is_home(&router, &config).await?,
+        Err(e) => return Err(e.context("Failed to get list of connected client")),
     };
+    let stale_note = stale_as_of.map(|at| {
+        let at = at.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M");
+        format!("STALE: the routers couldn't be reached, showing presence as of {at}")
+    });
+    let home_count = statuses.iter().filter(|s| s.home).count();
+    if let Some(template) = &args.template {
+        if let Some(note) = &stale_note {
+            eprintln!("{note}");
+        }
+        for status in &statuses {
+            println!("{}", render(template, status));
+        }
+        return Ok(());
+    }
+    match args.format {
+        OutputFormat::Text if quiet => println!(
+            "{}{}",
+            home_summary(home_count, statuses.len()),
+            if stale_note.is_some() { " (stale)" } else { "" }
+        ),
+        OutputFormat::Text => {
+            if let Some(note) = &stale_note {
+                println!("{note}");
+            }
+            // Everybody home first, then everybody away
+            let home = statuses.iter().filter(|s| s.home);
+            let away = statuses.iter().filter(|s| !s.home && args.show_away);
+            for status in home.chain(away) {
+                let mut duration = status_duration(status);
+                if let Some(device) = status.matched_device.as_ref().filter(|_| args.show_mac) {
+                    duration = format!("{duration}, {}", device.mac);
+                }
+                if status.home {
+                    let line = format!("{} is home ({duration})", status.name);
+                    if color {
+                        println!("{}", line.green());
+                    } else {
+                        println!("{line}");
+                    }
+                } else {
+                    let line = format!("{} is away ({duration})", status.name);
+                    if color {
+                        println!("{}", line.red().dimmed());
+                    } else {
+                        println!("{line}");
+                    }
+                }
+            }
+            println!("{}", home_summary(home_count, statuses.len()));
+        }
+        OutputFormat::Json => {
+            let whos_home = WhosHome::new(&statuses, stale_as_of);
+            println!("{}", serde_json::to_string(&whos_home)?);
+        }
+    }
 
     Ok(())
 }
 
-async fn show_who_is_home(router: &dyn router::Router, config: &Config) -> Result<()> {
-    let clients: Vec<_> = router
-        .online_clients()
+fn status_duration(status: &PersonStatus) -> String {
+    status
+        .duration_secs
+        .map(format_duration)
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+fn render(template: &str, status: &PersonStatus) -> String {
+    template
+        .replace("{name}", &status.name)
+        .replace("{status}", if status.home { "home" } else { "away" })
+        .replace("{duration}", &status_duration(status))
+}
+
+fn home_summary(home: usize, total: usize) -> String {
+    let people = if total == 1 { "person" } else { "people" };
+    format!("{home} of {total} {people} home")
+}
+
+/// Problems with the history only cost the durations, so they are logged
+/// rather than returned.
+fn load_history() -> Option<PresenceHistory> {
+    PresenceHistory::load()
+        .map_err(|e| warn!("Not keeping presence history: {e:#}"))
+        .ok()
+}
+
+/// The presence recorded the last time the routers could be reached, and
+/// when that was.
+fn last_known_statuses(
+    config: &Config,
+    history: Option<&PresenceHistory>,
+) -> Option<(Vec<PersonStatus>, chrono::DateTime<chrono::Utc>)> {
+    let history = history?;
+    let recorded_at = history.recorded_at()?;
+    let statuses = config
+        .persons
+        .iter()
+        .filter_map(|p| {
+            Some(PersonStatus {
+                name: p.name.clone(),
+                home: history.was_home(&p.name)?,
+                duration_secs: history.duration(&p.name).map(|d| d.as_secs()),
+                matched_device: None,
+            })
+        })
+        .collect();
+    Some((statuses, recorded_at))
+}
+
+/// Records the statuses in the history and fills in how long everybody has
+/// been home or away.
+fn record_history(history: &mut Option<PresenceHistory>, statuses: &mut [PersonStatus]) {
+    let Some(history) = history else {
+        return;
+    };
+    if let Err(e) = history.record(statuses.iter().map(|s| (s.name.as_str(), s.home))) {
+        warn!("Failed to record presence history: {e:#}");
+    }
+    for status in statuses {
+        status.duration_secs = history.duration(&status.name).map(|d| d.as_secs());
+    }
+}
+
+/// Color only when writing to a terminal, and never when asked not to.
+fn use_color(no_color: bool) -> bool {
+    !no_color && std::env::var_os("NO_COLOR").is_none() && std::io::stdout().is_terminal()
+}
+
+fn who_owns(config: &Config, device: &str) {
+    let owners = owners(config, device);
+    if owners.is_empty() {
+        println!("unassigned");
+    }
+    for owner in owners {
+        println!("{}", owner.name);
+    }
+}
+
+fn store_password(routers: &[Box<dyn Router>], config: &Config) -> Result<()> {
+    for (router, host) in routers.iter().zip(config.router_hosts()) {
+        let username = router.username()?;
+        let password = rpassword::prompt_password(format!("Password for {username} on {host}: "))
+            .context("Unable to read password")?;
+        credentials::store_password(host, &username, &password)?;
+    }
+    Ok(())
+}
+
+async fn login(routers: &[Box<dyn Router>], config: &Config) -> Result<()> {
+    for (router, host) in routers.iter().zip(config.router_hosts()) {
+        router
+            .known_clients()
+            .await
+            .with_context(|| format!("Failed to log in to {host}"))?;
+        println!("Logged in to {host}");
+    }
+    Ok(())
+}
+
+/// Devices that no router has ever seen are most likely typos or stale, but
+/// could also just have been offline for long, so they are only warned about.
+async fn doctor(routers: &[Box<dyn Router>], config: &Config) -> Result<()> {
+    let clients = known_clients(routers)
+        .await
+        .context("Failed to get list of known clients")?;
+    let mut unknown = 0;
+    for person in &config.persons {
+        for device in &person.devices {
+            if !clients
+                .iter()
+                .any(|c| device.names().any(|name| device_matches(name, c)))
+            {
+                println!(
+                    "Warning: {}'s device '{}' is not known by the routers",
+                    person.name,
+                    device.name()
+                );
+                unknown += 1;
+            }
+        }
+    }
+    if unknown == 0 {
+        println!("All configured devices are known by the routers");
+    }
+    Ok(())
+}
+
+async fn is_home_exit_code(
+    routers: &[Box<dyn Router>],
+    config: &Config,
+    person_name: &str,
+    verbose: bool,
+) -> Result<ExitCode> {
+    let person = config.person(person_name)?;
+    let clients = online_clients(routers)
         .await
         .context("Failed to get list of connected client")?;
-    trace!("Online clients {clients:?}");
+    if is_home(person, &clients) {
+        if verbose {
+            println!("{} is home", person.name);
+        }
+        Ok(ExitCode::SUCCESS)
+    } else {
+        if verbose {
+            println!("{} is away", person.name);
+        }
+        Ok(ExitCode::from(1))
+    }
+}
 
-    for person_home in config.persons.iter().filter(|p| {
-        p.devices
+async fn watch(
+    routers: &[Box<dyn Router>],
+    config: &Config,
+    interval: Duration,
+    notify: bool,
+    webhooks: Option<&Webhooks>,
+    max_unreachable: Option<u32>,
+) -> Result<()> {
+    let mut unreachable = 0;
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+    // Started while the routers reboot, it waits for them to come up
+    let clients = loop {
+        if let Some(clients) = poll(routers, &mut unreachable, max_unreachable).await? {
+            break clients;
+        }
+        tokio::select! {
+            _ = &mut ctrl_c => return Ok(()),
+            _ = tokio::time::sleep(interval) => {}
+        }
+    };
+    let mut previous = persons_home(config, &clients);
+    trace!("Initially home: {previous:?}");
+    let mut debounce = Debounce::new(config.away_after_polls.unwrap_or(1), previous.clone());
+    // Without the seen clients there are just no new device alerts
+    let mut seen = SeenClients::load()
+        .map_err(|e| warn!("Not reporting new devices: {e:#}"))
+        .ok();
+    report_new_devices(&mut seen, &clients, notify, webhooks).await;
+    let mut history = load_history();
+    record_history(&mut history, &mut statuses(config, &clients));
+
+    loop {
+        tokio::select! {
+            _ = &mut ctrl_c => return Ok(()),
+            _ = tokio::time::sleep(interval) => {}
+        }
+        // Presence is left as it was while the routers can't be reached
+        let Some(clients) = poll(routers, &mut unreachable, max_unreachable).await? else {
+            continue;
+        };
+        report_new_devices(&mut seen, &clients, notify, webhooks).await;
+        let current = debounce.update(persons_home(config, &clients));
+        let changes = changes(&previous, &current);
+        debug!("Presence changes: {}", Summary::new(&changes));
+        for change in changes {
+            let (name, event, before) = match change {
+                Change::Added(name) => (name, "arrived", "away"),
+                Change::Removed(name) => (name, "left", "home"),
+                Change::Changed { .. } => continue,
+            };
+            match history.as_ref().and_then(|h| h.duration(name)) {
+                Some(d) => println!(
+                    "{name} {event} after {} {before}",
+                    format_duration(d.as_secs())
+                ),
+                None => println!("{name} {event}"),
+            }
+            if notify {
+                send_notification(&change);
+            }
+            if let Some(webhooks) = webhooks {
+                webhooks.send(&change).await;
+            }
+        }
+        let mut current_statuses: Vec<_> = config
+            .persons
             .iter()
-            .any(|d| clients.iter().any(|c| &c.name == d))
-    }) {
-        println!("{} is home", person_home.name);
+            .map(|p| PersonStatus {
+                name: p.name.clone(),
+                home: current.contains(&p.name.as_str()),
+                duration_secs: None,
+                matched_device: None,
+            })
+            .collect();
+        record_history(&mut history, &mut current_statuses);
+        previous = current;
+    }
+}
+
+/// The online clients, or `None` while the routers can't be reached, e.g.
+/// while rebooting, until that has happened `max_unreachable` polls in a
+/// row. Other errors, such as failing to log in, end the watch.
+async fn poll(
+    routers: &[Box<dyn Router>],
+    unreachable: &mut u32,
+    max_unreachable: Option<u32>,
+) -> Result<Option<Vec<Client>>> {
+    systemd::notify_alive();
+    match online_clients(routers).await {
+        Ok(clients) => {
+            *unreachable = 0;
+            systemd::notify_ready();
+            Ok(Some(clients))
+        }
+        Err(e) if e.downcast_ref().is_some_and(RouterError::is_unreachable) => {
+            *unreachable += 1;
+            if max_unreachable.is_some_and(|max| *unreachable >= max) {
+                return Err(e.context(format!(
+                    "Routers unreachable for {unreachable} polls in a row"
+                )));
+            }
+            warn!("{e}, trying again next poll");
+            Ok(None)
+        }
+        Err(e) => Err(e.context("Failed to get list of connected client")),
+    }
+}
+
+async fn report_new_devices(
+    seen: &mut Option<SeenClients>,
+    clients: &[Client],
+    notify: bool,
+    webhooks: Option<&Webhooks>,
+) {
+    let Some(seen) = seen else {
+        return;
+    };
+    let new = match seen.record(clients) {
+        Ok(new) => new,
+        Err(e) => {
+            warn!("Failed to record seen clients: {e:#}");
+            return;
+        }
+    };
+    for client in new {
+        info!(
+            "New device on the network: {} ({})",
+            client.name, client.mac
+        );
+        println!("New device {} ({}) joined", client.name, client.mac);
+        if notify {
+            show_notification(&format!("New device {} joined", client.name), &client.mac);
+        }
+        if let Some(webhooks) = webhooks {
+            webhooks.send_new_device(&client).await;
+        }
+    }
+}
+
+async fn serve_metrics(
+    routers: &[Box<dyn Router>],
+    config: &Config,
+    listen: SocketAddr,
+    interval: Duration,
+) -> Result<()> {
+    let metrics = Arc::new(PresenceMetrics::new()?);
+    let server = metrics::serve(listen, metrics.clone(), shutdown_signal());
+    let poll = async {
+        loop {
+            match online_clients(routers).await {
+                Ok(clients) => {
+                    metrics.update(config, &clients);
+                    systemd::notify_ready();
+                }
+                Err(e) => warn!("Failed to get list of connected client: {e:#}"),
+            }
+            systemd::notify_alive();
+            tokio::time::sleep(interval).await;
+        }
+    };
+    tokio::select! {
+        result = server => result,
+        _ = poll => Ok(()),
+    }
+}
+
+async fn serve(
+    routers: &[Box<dyn Router>],
+    config: &Config,
+    address: SocketAddr,
+    interval: Duration,
+) -> Result<()> {
+    let shared = SharedStatuses::default();
+    let server = server::serve(address, shared.clone(), shutdown_signal());
+    let mut history = load_history();
+    let poll = async {
+        loop {
+            match online_clients(routers).await {
+                Ok(clients) => {
+                    let mut statuses = statuses(config, &clients);
+                    record_history(&mut history, &mut statuses);
+                    *shared.write().await = Some(Polled::new(statuses));
+                    systemd::notify_ready();
+                }
+                Err(e) => {
+                    warn!("Failed to get list of connected client: {e:#}");
+                    if let Some(polled) = shared.write().await.as_mut() {
+                        polled.stale = true;
+                    }
+                }
+            }
+            systemd::notify_alive();
+            tokio::time::sleep(interval).await;
+        }
+    };
+    tokio::select! {
+        result = server => result,
+        _ = poll => Ok(()),
     }
+}
+
+async fn publish_mqtt(
+    routers: &[Box<dyn Router>],
+    config: &Config,
+    interval: Duration,
+) -> Result<()> {
+    let mqtt_config = config
+        .mqtt
+        .as_ref()
+        .context("No mqtt section in the config")?;
+    let (publisher, mut event_loop) = MqttPublisher::new(mqtt_config);
+    let poll = async {
+        let mut previous: Option<Vec<&str>> = None;
+        loop {
+            match online_clients(routers).await {
+                Ok(clients) => {
+                    let current = persons_home(config, &clients);
+                    let published = match &previous {
+                        None => publish_all(&publisher, config, &current).await,
+                        Some(previous) => {
+                            publish_changes(&publisher, &changes(previous, &current)).await
+                        }
+                    };
+                    // Retry everything next time if the broker didn't take it
+                    match published {
+                        Ok(()) => {
+                            previous = Some(current);
+                            systemd::notify_ready();
+                        }
+                        Err(e) => warn!("{e:#}"),
+                    }
+                }
+                Err(e) => warn!("Failed to get list of connected client: {e:#}"),
+            }
+            systemd::notify_alive();
+            tokio::time::sleep(interval).await;
+        }
+    };
+    tokio::select! {
+        _ = poll => {}
+        _ = publisher.run(&mut event_loop) => {}
+        _ = shutdown_signal() => {}
+    }
+    publisher.shutdown(&mut event_loop).await
+}
 
+async fn publish_all(publisher: &MqttPublisher, config: &Config, home: &[&str]) -> Result<()> {
+    for person in &config.persons {
+        publisher
+            .publish_person(&person.name, home.contains(&person.name.as_str()))
+            .await?;
+    }
     Ok(())
 }
 
-pub fn configure_tracing() {
+async fn publish_changes(publisher: &MqttPublisher, changes: &[Change<&&str>]) -> Result<()> {
+    for change in changes {
+        match change {
+            Change::Added(name) => publisher.publish_person(name, true).await?,
+            Change::Removed(name) => publisher.publish_person(name, false).await?,
+            Change::Changed { .. } => {}
+        }
+    }
+    Ok(())
+}
+
+/// Completes on Ctrl-C or, on Unix, SIGTERM.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+                return;
+            }
+            Err(e) => warn!("Unable to listen for SIGTERM: {e}"),
+        }
+    }
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+fn send_notification(change: &Change<&&str>) {
+    let summary = match change {
+        Change::Added(name) => format!("{name} is now home"),
+        Change::Removed(name) => format!("{name} is now away"),
+        Change::Changed { .. } => return,
+    };
+    let body = format!("at {}", chrono::Local::now().format("%Y-%m-%d %H:%M:%S"));
+    show_notification(&summary, &body);
+}
+
+fn show_notification(summary: &str, body: &str) {
+    if let Err(e) = notify_rust::Notification::new()
+        .summary(summary)
+        .body(body)
+        .show()
+    {
+        warn!("Failed to show notification: {e}");
+    }
+}
+
+pub fn configure_tracing(verbosity: u8, log_file: Option<&Path>) -> Result<()> {
+    let level = match verbosity {
+        0 => "warn",
+        1 => "info",
+        2 => "debug",
+        _ => "trace",
+    };
+    let filter = tracing_subscriber::EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new(format!("whoshome={level}")));
+    let file_layer = match log_file {
+        Some(path) => {
+            // Appending makes each formatted line a single write, so
+            // concurrent runs don't interleave within a line.
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("Unable to open log file {}", path.display()))?;
+            Some(
+                tracing_subscriber::fmt::layer()
+                    .with_ansi(false)
+                    .with_writer(std::sync::Mutex::new(file)),
+            )
+        }
+        None => None,
+    };
     tracing_subscriber::registry()
-        .with(tracing_subscriber::EnvFilter::new("whoshome=trace"))
+        .with(filter)
         .with(tracing_subscriber::fmt::layer())
+        .with(file_layer)
         .init();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client() -> Client {
+        Client {
+            name: "Alices-iPhone".to_string(),
+            mac: "aa:bb:cc:00:00:01".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn confirms_the_action() {
+        let cli = Cli::try_parse_from(["whoshome", "block", "Alices-iPhone"]).unwrap();
+        assert_eq!(
+            confirmation(cli.quiet, Action::Block.past_tense(), &client()).as_deref(),
+            Some("Blocked Alices-iPhone (aa:bb:cc:00:00:01)")
+        );
+    }
+
+    #[test]
+    fn quiet_suppresses_the_confirmation() {
+        let cli = Cli::try_parse_from(["whoshome", "--quiet", "block", "Alices-iPhone"]).unwrap();
+        assert_eq!(
+            confirmation(cli.quiet, Action::Block.past_tense(), &client()),
+            None
+        );
+    }
+
+    #[test]
+    fn summarizes_how_many_are_home() {
+        assert_eq!(home_summary(0, 2), "0 of 2 people home");
+        assert_eq!(home_summary(0, 1), "0 of 1 person home");
+        assert_eq!(home_summary(1, 1), "1 of 1 person home");
+        assert_eq!(home_summary(3, 4), "3 of 4 people home");
+    }
+
+    #[test]
+    fn renders_each_placeholder() {
+        let mut status = PersonStatus {
+            name: "Alice".to_string(),
+            home: true,
+            duration_secs: Some(2 * 3600 + 5 * 60),
+            matched_device: None,
+        };
+        assert_eq!(
+            render("{name} is {status} ({duration}) {name}", &status),
+            "Alice is home (2h 5m) Alice"
+        );
+        status.home = false;
+        status.duration_secs = None;
+        assert_eq!(
+            render("{name}: {status} for {duration}", &status),
+            "Alice: away for unknown"
+        );
+        assert_eq!(render("{name} {device}", &status), "Alice {device}");
+    }
 }
@@ -0,0 +1,13 @@
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Context, Result};
+
+/// The directory whoshome keeps state between runs in, created if missing.
+pub fn cache_dir() -> Result<PathBuf> {
+    let dir = dirs::cache_dir()
+        .ok_or_else(|| anyhow!("Unable to find cache dir"))?
+        .join("whoshome");
+    std::fs::create_dir_all(&dir)
+        .with_context(|| format!("Unable to create cache dir {}", dir.display()))?;
+    Ok(dir)
+}
@@ -0,0 +1,302 @@
+use std::{collections::HashSet, hash::Hash};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change<T> {
+    Added(T),
+    Removed(T),
+    /// Only produced by [`changes_with`].
+    Changed {
+        before: T,
+        after: T,
+    },
+}
+
+impl<T> Change<T> {
+    /// The item, or for [`Change::Changed`] the item after the change.
+    pub fn value(&self) -> &T {
+        match self {
+            Change::Added(x) | Change::Removed(x) => x,
+            Change::Changed { after, .. } => after,
+        }
+    }
+}
+
+/// The items added in `after` and removed from `before`.
+///
+/// The order is fixed: first the added items in the order they have in
+/// `after`, then the removed items in the order they have in `before`.
+pub fn changes<'t, T: Eq>(before: &'t [T], after: &'t [T]) -> Vec<Change<&'t T>> {
+    changes_by_key(before, after, |x| x)
+}
+
+/// The same changes as [`changes`], in the same order, computed lazily.
+pub fn changes_iter<'t, T: Eq>(
+    before: &'t [T],
+    after: &'t [T],
+) -> impl Iterator<Item = Change<&'t T>> {
+    diff(before, after, |x| before.contains(x), |x| after.contains(x))
+}
+
+/// Like [`changes`], but items are compared by the key extracted by `key`,
+/// so e.g. clients can be compared by MAC while ignoring renames. The order
+/// is the same as for [`changes`].
+pub fn changes_by_key<'t, T, K: Eq>(
+    before: &'t [T],
+    after: &'t [T],
+    key: impl Fn(&'t T) -> K,
+) -> Vec<Change<&'t T>> {
+    let before_keys: Vec<K> = before.iter().map(&key).collect();
+    let after_keys: Vec<K> = after.iter().map(&key).collect();
+    diff(
+        before,
+        after,
+        |x| before_keys.contains(&key(x)),
+        |x| after_keys.contains(&key(x)),
+    )
+    .collect()
+}
+
+/// Like [`changes`], but sorted by item instead.
+pub fn changes_sorted<'t, T: Ord>(before: &'t [T], after: &'t [T]) -> Vec<Change<&'t T>> {
+    let mut changes = changes(before, after);
+    changes.sort_by(|a, b| a.value().cmp(b.value()));
+    changes
+}
+
+/// Same result as [`changes`], but builds hash sets of the items first so it
+/// runs in linear rather than quadratic time on large inputs.
+pub fn changes_hashed<'t, T: Hash + Eq>(before: &'t [T], after: &'t [T]) -> Vec<Change<&'t T>> {
+    let before_set: HashSet<&T> = before.iter().collect();
+    let after_set: HashSet<&T> = after.iter().collect();
+    diff(
+        before,
+        after,
+        |x| before_set.contains(x),
+        |x| after_set.contains(x),
+    )
+    .collect()
+}
+
+/// The changes in the order of [`changes`], given how to tell if an item is
+/// in `before` and in `after`.
+fn diff<'t, T>(
+    before: &'t [T],
+    after: &'t [T],
+    in_before: impl Fn(&'t T) -> bool,
+    in_after: impl Fn(&'t T) -> bool,
+) -> impl Iterator<Item = Change<&'t T>> {
+    after
+        .iter()
+        .filter(move |x| !in_before(x))
+        .map(Change::Added)
+        .chain(
+            before
+                .iter()
+                .filter(move |x| !in_after(x))
+                .map(Change::Removed),
+        )
+}
+
+/// Like [`changes_by_key`], but items whose key is in both `before` and
+/// `after` and that `eq` doesn't consider equal are reported as
+/// [`Change::Changed`], after the additions and removals, in `after` order.
+pub fn changes_with<'t, T, K: Eq>(
+    before: &'t [T],
+    after: &'t [T],
+    key: impl Fn(&'t T) -> K,
+    eq: impl Fn(&T, &T) -> bool,
+) -> Vec<Change<&'t T>> {
+    let mut result = changes_by_key(before, after, &key);
+    let before_keyed: Vec<(K, &T)> = before.iter().map(|x| (key(x), x)).collect();
+    for a in after {
+        let k = key(a);
+        if let Some((_, b)) = before_keyed.iter().find(|(bk, _)| *bk == k) {
+            if !eq(b, a) {
+                result.push(Change::Changed {
+                    before: b,
+                    after: a,
+                });
+            }
+        }
+    }
+    result
+}
+
+/// Counts of and access to the kinds of changes in a list of changes.
+pub struct Summary<'c, T> {
+    changes: &'c [Change<T>],
+}
+
+impl<'c, T> Summary<'c, T> {
+    pub fn new(changes: &'c [Change<T>]) -> Self {
+        Self { changes }
+    }
+
+    pub fn added(&self) -> impl Iterator<Item = &'c T> {
+        self.changes.iter().filter_map(|c| match c {
+            Change::Added(x) => Some(x),
+            _ => None,
+        })
+    }
+
+    pub fn removed(&self) -> impl Iterator<Item = &'c T> {
+        self.changes.iter().filter_map(|c| match c {
+            Change::Removed(x) => Some(x),
+            _ => None,
+        })
+    }
+
+    pub fn added_count(&self) -> usize {
+        self.added().count()
+    }
+
+    pub fn removed_count(&self) -> usize {
+        self.removed().count()
+    }
+
+    pub fn changed_count(&self) -> usize {
+        self.changes
+            .iter()
+            .filter(|c| matches!(c, Change::Changed { .. }))
+            .count()
+    }
+}
+
+impl<T> std::fmt::Display for Summary<'_, T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} added, {} removed, {} changed",
+            self.added_count(),
+            self.removed_count(),
+            self.changed_count()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use Change::{Added, Removed};
+
+    #[test]
+    fn added_in_after_order_then_removed_in_before_order() {
+        let before = ["d", "b", "x", "a"];
+        let after = ["x", "e", "c", "f"];
+        assert_eq!(
+            changes(&before, &after),
+            [
+                Added(&"e"),
+                Added(&"c"),
+                Added(&"f"),
+                Removed(&"d"),
+                Removed(&"b"),
+                Removed(&"a")
+            ]
+        );
+        assert_eq!(
+            changes_iter(&before, &after).collect::<Vec<_>>(),
+            changes(&before, &after)
+        );
+    }
+
+    #[test]
+    fn sorted_mixes_added_and_removed() {
+        let before = ["d", "b", "x", "a"];
+        let after = ["x", "e", "c", "f"];
+        assert_eq!(
+            changes_sorted(&before, &after),
+            [
+                Removed(&"a"),
+                Removed(&"b"),
+                Added(&"c"),
+                Removed(&"d"),
+                Added(&"e"),
+                Added(&"f")
+            ]
+        );
+    }
+
+    #[test]
+    fn hashed_gives_the_same_changes_on_large_input() {
+        // Scrambled, partly overlapping and with duplicates
+        let before: Vec<u32> = (0..3000).map(|i| i * 7919 % 4000).collect();
+        let after: Vec<u32> = (0..3000)
+            .map(|i| (i * 104_729 + 1000) % 4000 / 2 * 2)
+            .collect();
+        let expected = changes(&before, &after);
+        assert!(!expected.is_empty());
+        assert_eq!(changes_hashed(&before, &after), expected);
+    }
+
+    /// (MAC, name) pairs, keyed by MAC.
+    fn with_names<'t>(
+        before: &'t [(&'t str, &'t str)],
+        after: &'t [(&'t str, &'t str)],
+    ) -> Vec<Change<&'t (&'t str, &'t str)>> {
+        changes_with(before, after, |(mac, _)| *mac, |a, b| a.1 == b.1)
+    }
+
+    #[test]
+    fn with_only_adds_and_removes_when_no_keys_collide() {
+        let before = [("01", "phone"), ("02", "laptop")];
+        let after = [("03", "tv")];
+        let changes = with_names(&before, &after);
+        assert_eq!(
+            changes,
+            [Added(&after[0]), Removed(&before[0]), Removed(&before[1])]
+        );
+    }
+
+    #[test]
+    fn with_reports_a_value_difference_as_changed() {
+        let before = [("01", "phone"), ("02", "laptop")];
+        let after = [("02", "laptop"), ("01", "Alices-iPhone")];
+        assert_eq!(
+            with_names(&before, &after),
+            [Change::Changed {
+                before: &before[0],
+                after: &after[1]
+            }]
+        );
+    }
+
+    #[test]
+    fn summary_of_no_changes() {
+        let changes: Vec<Change<&str>> = Vec::new();
+        let summary = Summary::new(&changes);
+        assert_eq!(summary.added().count(), 0);
+        assert_eq!(summary.removed().count(), 0);
+        assert_eq!(summary.to_string(), "0 added, 0 removed, 0 changed");
+    }
+
+    #[test]
+    fn summary_of_additions_only() {
+        let changes = changes(&[], &["alice", "bob"]);
+        let summary = Summary::new(&changes);
+        assert_eq!(summary.added().collect::<Vec<_>>(), [&&"alice", &&"bob"]);
+        assert_eq!(summary.removed_count(), 0);
+        assert_eq!(summary.to_string(), "2 added, 0 removed, 0 changed");
+    }
+
+    #[test]
+    fn summary_of_removals_only() {
+        let changes = changes(&["alice", "bob"], &[]);
+        let summary = Summary::new(&changes);
+        assert_eq!(summary.added_count(), 0);
+        assert_eq!(summary.removed().collect::<Vec<_>>(), [&&"alice", &&"bob"]);
+        assert_eq!(summary.to_string(), "0 added, 2 removed, 0 changed");
+    }
+
+    #[test]
+    fn summary_of_mixed_changes() {
+        let before = [("01", "phone"), ("02", "laptop")];
+        let after = [("01", "Alices-iPhone"), ("03", "tv")];
+        let changes = with_names(&before, &after);
+        let summary = Summary::new(&changes);
+        assert_eq!(summary.added().collect::<Vec<_>>(), [&&after[1]]);
+        assert_eq!(summary.removed().collect::<Vec<_>>(), [&&before[1]]);
+        assert_eq!(summary.to_string(), "1 added, 1 removed, 1 changed");
+    }
+}
@@ -0,0 +1,297 @@
+use std::collections::HashSet;
+
+use anyhow::{anyhow, bail, Context, Result};
+use futures::future::try_join_all;
+
+use crate::{
+    config::Device,
+    presence::device_matches,
+    router::{merge_clients, parse_mac, Client, Router, RouterError},
+};
+
+/// The client known by the router as `client_name`, a name or a MAC.
+pub async fn find_client(router: &dyn Router, client_name: &str) -> Result<Client> {
+    let clients = router.known_clients().await?;
+    find_in(&clients, client_name)
+}
+
+/// Like [`find_client`], but looking in an already fetched list.
+pub fn find_in(clients: &[Client], client_name: &str) -> Result<Client> {
+    let matches: Vec<_> = clients
+        .iter()
+        .filter(|c| device_matches(client_name, c))
+        .collect();
+    match matches[..] {
+        [client] => Ok(client.clone()),
+        [_, _, ..] => bail!(
+            "Several clients are named '{client_name}' ({}); name one by its MAC instead",
+            matches
+                .iter()
+                .map(|c| c.mac.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        [] => Err(RouterError::ClientNotFound {
+            name: client_name.to_string(),
+            suggestions: suggestions(client_name, clients.iter().map(|c| c.name.as_str()))
+                .into_iter()
+                .map(str::to_string)
+                .collect(),
+        }
+        .into()),
+    }
+}
+
+/// The client for the first of the device's names known to the router or,
+/// since a MAC can be blocked even if the router hasn't seen it yet, for its
+/// MAC. Gives up on a name that matches several clients.
+pub fn find_device(clients: &[Client], device: &Device) -> Result<Client> {
+    for name in device.names() {
+        match find_in(clients, name) {
+            Ok(client) => return Ok(client),
+            Err(e) if !is_not_found(&e) => return Err(e),
+            Err(_) => {}
+        }
+    }
+    client_from_mac(device.name()).map_err(|_| anyhow!("not known by any router"))
+}
+
+/// Whether `error` is from [`find_in`] finding no client by the name.
+pub fn is_not_found(error: &anyhow::Error) -> bool {
+    matches!(
+        error.downcast_ref(),
+        Some(RouterError::ClientNotFound { .. })
+    )
+}
+
+/// Up to three of `candidates` closest to `name`, closest first.
+fn suggestions<'a>(name: &str, candidates: impl Iterator<Item = &'a str>) -> Vec<&'a str> {
+    let name = name.trim().to_lowercase();
+    let max_distance = (name.chars().count() / 2).max(2);
+    let mut scored: Vec<_> = candidates
+        .map(|c| (strsim::levenshtein(&name, &c.to_lowercase()), c))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .collect();
+    scored.sort_by_key(|(distance, _)| *distance);
+    let mut seen = HashSet::new();
+    scored
+        .into_iter()
+        .filter(|(_, c)| seen.insert(c.to_lowercase()))
+        .take(3)
+        .map(|(_, c)| c)
+        .collect()
+}
+
+/// A client for a MAC address the router doesn't need to know by name.
+pub fn client_from_mac(mac: &str) -> Result<Client> {
+    let mac = parse_mac(mac).with_context(|| format!("{mac} is not a valid MAC address"))?;
+    Ok(Client {
+        name: mac.clone(),
+        mac,
+        ..Default::default()
+    })
+}
+
+/// The clients online on any of the routers, queried concurrently.
+pub async fn online_clients(routers: &[Box<dyn Router>]) -> Result<Vec<Client>> {
+    let lists = try_join_all(routers.iter().map(|r| r.online_clients())).await?;
+    Ok(merge_clients(lists))
+}
+
+/// The clients known by any of the routers, queried concurrently.
+pub async fn known_clients(routers: &[Box<dyn Router>]) -> Result<Vec<Client>> {
+    let lists = try_join_all(routers.iter().map(|r| r.known_clients())).await?;
+    Ok(merge_clients(lists))
+}
+
+/// The clients known by each of the routers, so that a command about a
+/// client can be sent to the router that knows it.
+pub struct KnownClients<'r> {
+    routers: &'r [Box<dyn Router>],
+    lists: Vec<Vec<Client>>,
+    merged: Vec<Client>,
+}
+
+impl<'r> KnownClients<'r> {
+    /// Queries the routers concurrently.
+    pub async fn fetch(routers: &'r [Box<dyn Router>]) -> Result<Self> {
+        let lists = try_join_all(routers.iter().map(|r| r.known_clients()))
+            .await
+            .context("Failed to get list of known clients")?;
+        let merged = merge_clients(lists.clone());
+        Ok(Self {
+            routers,
+            lists,
+            merged,
+        })
+    }
+
+    /// The clients known by any of the routers, the first of each MAC.
+    pub fn all(&self) -> &[Client] {
+        &self.merged
+    }
+
+    /// The first router that knows the client, or the first router if none
+    /// does, e.g. for a MAC that hasn't been seen yet.
+    pub fn router_for(&self, client: &Client) -> &'r dyn Router {
+        let index = self
+            .lists
+            .iter()
+            .position(|list| list.iter().any(|c| c.mac == client.mac))
+            .unwrap_or(0);
+        self.routers[index].as_ref()
+    }
+
+    /// Each client with the router to send commands about it to, as picked
+    /// by [`router_for`](Self::router_for).
+    pub fn on_routers(&self, clients: Vec<Client>) -> Vec<(&'r dyn Router, Client)> {
+        clients
+            .into_iter()
+            .map(|client| (self.router_for(&client), client))
+            .collect()
+    }
+
+    /// Every client known by each router, so a client known by several
+    /// routers comes once for each of them.
+    pub fn by_router(&self) -> impl Iterator<Item = (&'r dyn Router, &Client)> + '_ {
+        self.routers
+            .iter()
+            .zip(&self.lists)
+            .flat_map(|(router, list)| list.iter().map(move |c| (router.as_ref(), c)))
+    }
+}
+
+/// The known and the online clients, fetched concurrently.
+pub async fn known_and_online(routers: &[Box<dyn Router>]) -> Result<(Vec<Client>, Vec<Client>)> {
+    tokio::try_join!(
+        async {
+            known_clients(routers)
+                .await
+                .context("Failed to get list of known clients")
+        },
+        async {
+            online_clients(routers)
+                .await
+                .context("Failed to get list of connected client")
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{mock_router::MockRouter, test_util::client};
+
+    #[tokio::test]
+    async fn commands_go_to_the_router_that_knows_the_client() {
+        let routers: Vec<Box<dyn Router>> = vec![
+            Box::new(MockRouter::with_online(vec![client(
+                "Alices-iPhone",
+                "aa:bb:cc:00:00:01",
+            )])),
+            Box::new(MockRouter::with_online(vec![client(
+                "garage-tv",
+                "aa:bb:cc:00:00:02",
+            )])),
+        ];
+        let known = KnownClients::fetch(&routers).await.unwrap();
+        let tv = find_in(known.all(), "garage-tv").unwrap();
+        assert!(std::ptr::addr_eq(
+            known.router_for(&tv),
+            routers[1].as_ref()
+        ));
+        let phone = find_in(known.all(), "alices-iphone").unwrap();
+        assert!(std::ptr::addr_eq(
+            known.router_for(&phone),
+            routers[0].as_ref()
+        ));
+        let unseen = client_from_mac("aa:bb:cc:00:00:03").unwrap();
+        assert!(std::ptr::addr_eq(
+            known.router_for(&unseen),
+            routers[0].as_ref()
+        ));
+    }
+
+    #[tokio::test]
+    async fn by_router_has_a_client_once_for_each_router_knowing_it() {
+        let blocked = Client {
+            blocked: true,
+            ..client("garage-tv", "aa:bb:cc:00:00:02")
+        };
+        let routers: Vec<Box<dyn Router>> = vec![
+            Box::new(MockRouter::with_online(vec![blocked.clone()])),
+            Box::new(MockRouter::with_online(vec![
+                client("Alices-iPhone", "aa:bb:cc:00:00:01"),
+                blocked,
+            ])),
+        ];
+        let known = KnownClients::fetch(&routers).await.unwrap();
+        assert_eq!(known.all().len(), 2);
+        let tvs: Vec<_> = known.by_router().filter(|(_, c)| c.blocked).collect();
+        assert_eq!(tvs.len(), 2);
+        assert!(std::ptr::addr_eq(tvs[0].0, routers[0].as_ref()));
+        assert!(std::ptr::addr_eq(tvs[1].0, routers[1].as_ref()));
+    }
+
+    #[test]
+    fn clients_sharing_a_name_have_to_be_picked_by_mac() {
+        let clients = [
+            client("iPhone", "aa:bb:cc:00:00:01"),
+            client("iphone", "aa:bb:cc:00:00:02"),
+        ];
+        let error = find_in(&clients, "iPhone").unwrap_err().to_string();
+        assert_eq!(
+            error,
+            "Several clients are named 'iPhone' (aa:bb:cc:00:00:01, aa:bb:cc:00:00:02); \
+             name one by its MAC instead"
+        );
+        let client = find_in(&clients, "AA-BB-CC-00-00-02").unwrap();
+        assert_eq!(client.mac, "aa:bb:cc:00:00:02");
+    }
+
+    #[test]
+    fn device_is_found_by_any_of_its_names_or_its_mac() {
+        let clients = [
+            client("iPhone", "aa:bb:cc:00:00:01"),
+            client("iphone", "aa:bb:cc:00:00:02"),
+            client("garage-tv", "aa:bb:cc:00:00:03"),
+        ];
+        let tv = Device::WithAliases {
+            name: "tv".to_string(),
+            aliases: vec!["garage-tv".to_string()],
+            active_hours: None,
+        };
+        assert_eq!(find_device(&clients, &tv).unwrap().mac, "aa:bb:cc:00:00:03");
+        let unseen = Device::Name("aa:bb:cc:00:00:04".to_string());
+        assert_eq!(
+            find_device(&clients, &unseen).unwrap().mac,
+            "aa:bb:cc:00:00:04"
+        );
+        let error = find_device(&clients, &Device::Name("tablet".to_string())).unwrap_err();
+        assert_eq!(error.to_string(), "not known by any router");
+        let error = find_device(&clients, &Device::Name("iPhone".to_string())).unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("Several clients are named 'iPhone'"));
+    }
+
+    #[test]
+    fn suggests_the_three_closest_names_once_each() {
+        let candidates = ["tv10", "tv1", "TV1", "tv2", "tv3", "radio"];
+        assert_eq!(
+            suggestions("TV", candidates.into_iter()),
+            ["tv1", "tv2", "tv3"]
+        );
+        assert_eq!(
+            suggestions("tv", ["tv100", "tv10", "radio"].into_iter()),
+            ["tv10"]
+        );
+        let error = find_in(&[client("garage-tv", "aa:bb:cc:00:00:01")], "garage-tb")
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            error,
+            "Could not find client named 'garage-tb'; did you mean 'garage-tv'?"
+        );
+    }
+}
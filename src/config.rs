@@ -1,22 +1,432 @@
-use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
 use serde::Deserialize;
+use tracing::debug;
+
+use crate::{
+    credentials::CredentialSource,
+    http::ProxyConfig,
+    mqtt::MqttConfig,
+    presence::{devices_match, names_match, owners},
+    router::RouterKind,
+    schedule::{ScheduleEntry, TimeWindow},
+    unifi_dream_router::ApiStyle,
+};
 
 #[derive(Deserialize)]
 pub struct Config {
     pub router: String,
+    #[serde(default)]
+    pub routers: Vec<String>,
+    pub username: Option<String>,
+    #[serde(default)]
+    pub router_kind: RouterKind,
+    #[serde(default)]
+    pub api_style: ApiStyle,
+    pub site: Option<String>,
+    /// Sites on the same controller to look for clients on, along with `site`
+    /// if set.
+    #[serde(default)]
+    pub sites: Vec<String>,
+    pub port: Option<u16>,
+    pub timeout_secs: Option<u64>,
+    #[serde(default)]
+    pub accept_invalid_certs: bool,
+    pub ca_cert: Option<PathBuf>,
+    pub cert_fingerprint: Option<String>,
+    pub proxy: Option<ProxyConfig>,
+    pub retries: Option<u32>,
+    pub retry_base_delay_ms: Option<u64>,
+    /// Pause between block/unblock commands sent in a batch.
+    pub command_delay_ms: Option<u64>,
+    pub away_after_polls: Option<u32>,
     pub persons: Vec<Person>,
+    pub credential_order: Option<Vec<CredentialSource>>,
+    pub netrc: Option<PathBuf>,
+    pub mqtt: Option<MqttConfig>,
+    #[serde(default)]
+    pub webhooks: Vec<String>,
+    #[serde(default)]
+    pub schedule: Vec<ScheduleEntry>,
+}
+
+impl Config {
+    /// The primary router followed by any additional ones.
+    pub fn router_hosts(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.router.as_str()).chain(self.routers.iter().map(String::as_str))
+    }
+
+    /// The person named `name`, ignoring case and surrounding space.
+    pub fn person(&self, name: &str) -> Result<&Person> {
+        self.persons
+            .iter()
+            .find(|p| names_match(&p.name, name))
+            .with_context(|| format!("Could not find person named {name}"))
+    }
+
+    /// Checks for mistakes that parse fine but make presence wrong, reporting
+    /// all of them at once.
+    pub fn validate(&self) -> Result<()> {
+        let mut problems = Vec::new();
+        if self.router.trim().is_empty() {
+            problems.push("router is empty".to_string());
+        }
+        for (i, person) in self.persons.iter().enumerate() {
+            let earlier = &self.persons[..i];
+            if earlier.iter().any(|p| names_match(&p.name, &person.name)) {
+                problems.push(format!("{} is listed more than once", person.name));
+            }
+            if person.devices.is_empty() {
+                problems.push(format!("{} has no devices", person.name));
+            }
+            if let Match::Primary(primary) = &person.match_mode {
+                if !person
+                    .devices
+                    .iter()
+                    .flat_map(Device::names)
+                    .any(|d| devices_match(d, primary))
+                {
+                    problems.push(format!(
+                        "{}'s primary device {primary} isn't one of their devices",
+                        person.name
+                    ));
+                }
+            }
+        }
+        // Each shared device is reported once, with all of its owners
+        let mut shared: Vec<&str> = Vec::new();
+        for device in self
+            .persons
+            .iter()
+            .flat_map(|p| &p.devices)
+            .flat_map(Device::names)
+        {
+            if shared.iter().any(|d| devices_match(d, device)) {
+                continue;
+            }
+            let owners: Vec<_> = owners(self, device)
+                .iter()
+                .map(|p| p.name.as_str())
+                .collect();
+            if let [first @ .., last] = owners.as_slice() {
+                if !first.is_empty() {
+                    problems.push(format!(
+                        "{device} is assigned to {} and {last}",
+                        first.join(", ")
+                    ));
+                    shared.push(device);
+                }
+            }
+        }
+        if problems.is_empty() {
+            return Ok(());
+        }
+        Err(anyhow!(
+            "{} problem(s) in the config:\n  {}",
+            problems.len(),
+            problems.join("\n  ")
+        ))
+    }
 }
 
 #[derive(Deserialize, Debug)]
 pub struct Person {
     pub name: String,
-    pub devices: Vec<String>,
+    pub devices: Vec<Device>,
+    /// Only count the devices as presence during these hours.
+    pub active_hours: Option<TimeWindow>,
+    #[serde(rename = "match", default)]
+    pub match_mode: Match,
 }
 
-pub fn get_config() -> Result<Config> {
-    let config = std::fs::read_to_string("config.dhall").context("Unable to read config.dhall")?;
-    let config = serde_dhall::from_str(&config)
-        .parse()
-        .context("Failed to parse config.dhall")?;
+/// Which of a person's devices have to be online for them to be home.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Match {
+    /// Any of the devices.
+    #[default]
+    Any,
+    /// Every one of the devices.
+    All,
+    /// The given device, one of the person's own, e.g. the phone that goes
+    /// everywhere with them while their laptop stays home.
+    Primary(String),
+}
+
+/// A device is either just its name (or MAC), or a name with aliases for
+/// when the router reports the same device under several names.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Device {
+    Name(String),
+    WithAliases {
+        name: String,
+        #[serde(default)]
+        aliases: Vec<String>,
+        /// Only count the device as presence during these hours, e.g. for
+        /// a computer that stays online when its owner is out.
+        active_hours: Option<TimeWindow>,
+    },
+}
+
+impl Device {
+    pub fn name(&self) -> &str {
+        match self {
+            Device::Name(name) => name,
+            Device::WithAliases { name, .. } => name,
+        }
+    }
+
+    pub fn active_hours(&self) -> Option<&TimeWindow> {
+        match self {
+            Device::Name(_) => None,
+            Device::WithAliases { active_hours, .. } => active_hours.as_ref(),
+        }
+    }
+
+    /// The name followed by any aliases.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        let aliases = match self {
+            Device::Name(_) => &[][..],
+            Device::WithAliases { aliases, .. } => aliases,
+        };
+        std::iter::once(self.name()).chain(aliases.iter().map(String::as_str))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Dhall,
+    Toml,
+}
+
+impl ConfigFormat {
+    pub fn from_path(path: &Path) -> Result<Self> {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("dhall") => Ok(ConfigFormat::Dhall),
+            Some("toml") => Ok(ConfigFormat::Toml),
+            _ => Err(anyhow!(
+                "Unknown config format for {}, expected .dhall or .toml",
+                path.display()
+            )),
+        }
+    }
+}
+
+/// The Dhall type of [`Config`], ending in an example config using it.
+pub const DHALL_SCHEMA: &str = include_str!("schema.dhall");
+
+pub const CONFIG_ENV_VAR: &str = "WHOSHOME_CONFIG";
+
+/// Reads the config from `path` if given, otherwise from `$WHOSHOME_CONFIG`,
+/// otherwise from `config.toml` or `config.dhall` in the user's config dir
+/// (e.g. `~/.config/whoshome`) or else the current directory.
+pub fn get_config(path: Option<&Path>) -> Result<Config> {
+    let path = match path {
+        Some(path) => path.to_path_buf(),
+        None => match std::env::var_os(CONFIG_ENV_VAR) {
+            Some(path) => PathBuf::from(path),
+            None => default_path(),
+        },
+    };
+    debug!("Reading config from {}", path.display());
+    read_config(&path)
+}
+
+/// The first config file found in the config dir or the current directory,
+/// or `config.dhall` if there is none so the error names a likely file.
+fn default_path() -> PathBuf {
+    let dirs = dirs::config_dir()
+        .map(|dir| dir.join("whoshome"))
+        .into_iter()
+        .chain([PathBuf::new()]);
+    dirs.flat_map(|dir| [dir.join("config.toml"), dir.join("config.dhall")])
+        .find(|path| path.exists())
+        .unwrap_or_else(|| PathBuf::from("config.dhall"))
+}
+
+pub fn read_config(path: &Path) -> Result<Config> {
+    let format = ConfigFormat::from_path(path)?;
+    let config = std::fs::read_to_string(path)
+        .with_context(|| format!("Unable to read {}", path.display()))?;
+    let config: Config = match format {
+        ConfigFormat::Dhall => serde_dhall::from_str(&config)
+            .parse()
+            .with_context(|| format!("Failed to parse {}", path.display()))?,
+        ConfigFormat::Toml => toml::from_str(&config)
+            .with_context(|| format!("Failed to parse {}", path.display()))?,
+    };
+    config.validate()?;
     Ok(config)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::config;
+
+    #[test]
+    fn primary_must_be_one_of_the_devices() {
+        let config = config(
+            r#"
+            [[persons]]
+            name = "Alice"
+            devices = ["Alices-iPhone"]
+            match = { primary = "alice-laptop" }
+            "#,
+        );
+        let error = config.validate().unwrap_err().to_string();
+        assert!(
+            error.contains("Alice's primary device alice-laptop isn't one of their devices"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn primary_may_be_an_alias() {
+        let config = config(
+            r#"
+            [[persons]]
+            name = "Alice"
+            devices = [{ name = "Alices-iPhone", aliases = ["iPhone"] }]
+            match = { primary = "iphone" }
+            "#,
+        );
+        config.validate().unwrap();
+    }
+
+    // One test, as the environment is shared with the tests running alongside
+    #[test]
+    fn config_from_the_env_var_unless_given_a_path() {
+        let dir = std::env::temp_dir();
+        let from_env = dir.join(format!("whoshome-{}-env.toml", std::process::id()));
+        let given = dir.join(format!("whoshome-{}-given.toml", std::process::id()));
+        std::fs::write(&from_env, "router = \"10.0.0.1\"\npersons = []").unwrap();
+        std::fs::write(&given, "router = \"10.0.0.2\"\npersons = []").unwrap();
+        std::env::set_var(CONFIG_ENV_VAR, &from_env);
+        let env_router = get_config(None).map(|c| c.router);
+        let given_router = get_config(Some(&given)).map(|c| c.router);
+        std::env::remove_var(CONFIG_ENV_VAR);
+        std::fs::remove_file(&from_env).unwrap();
+        std::fs::remove_file(&given).unwrap();
+        assert_eq!(env_router.unwrap(), "10.0.0.1");
+        assert_eq!(given_router.unwrap(), "10.0.0.2");
+    }
+
+    #[test]
+    fn person_by_name_in_any_case() {
+        let config = config(
+            r#"
+            [[persons]]
+            name = "Alice"
+            devices = ["Alices-iPhone"]
+            "#,
+        );
+        assert_eq!(config.person("aLiCe ").unwrap().name, "Alice");
+        assert!(config.person("Bob").is_err());
+    }
+
+    fn problems(config: &Config) -> String {
+        config.validate().unwrap_err().to_string()
+    }
+
+    #[test]
+    fn persons_listed_twice() {
+        let config = config(
+            r#"
+            [[persons]]
+            name = "Alice"
+            devices = ["Alices-iPhone"]
+
+            [[persons]]
+            name = "alice"
+            devices = ["alice-laptop"]
+            "#,
+        );
+        assert_eq!(
+            problems(&config),
+            "1 problem(s) in the config:\n  alice is listed more than once"
+        );
+    }
+
+    #[test]
+    fn person_without_devices() {
+        let config = config(
+            r#"
+            [[persons]]
+            name = "Alice"
+            devices = []
+            "#,
+        );
+        assert_eq!(
+            problems(&config),
+            "1 problem(s) in the config:\n  Alice has no devices"
+        );
+    }
+
+    #[test]
+    fn empty_router() {
+        let config: Config = toml::from_str("router = \" \"\npersons = []").unwrap();
+        assert_eq!(
+            problems(&config),
+            "1 problem(s) in the config:\n  router is empty"
+        );
+    }
+
+    #[test]
+    fn all_problems_are_reported_at_once() {
+        let config: Config = toml::from_str(
+            r#"
+            router = ""
+
+            [[persons]]
+            name = "Alice"
+            devices = []
+
+            [[persons]]
+            name = "Alice"
+            devices = ["Alices-iPhone"]
+            match = { primary = "alice-laptop" }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            problems(&config),
+            "4 problem(s) in the config:\n  \
+             router is empty\n  \
+             Alice has no devices\n  \
+             Alice is listed more than once\n  \
+             Alice's primary device alice-laptop isn't one of their devices"
+        );
+    }
+
+    #[test]
+    fn shared_device_is_reported_once_with_all_owners() {
+        let config = config(
+            r#"
+            [[persons]]
+            name = "Alice"
+            devices = ["family-ipad", "Alices-iPhone"]
+
+            [[persons]]
+            name = "Bob"
+            devices = ["Family-iPad "]
+            "#,
+        );
+        assert_eq!(
+            problems(&config),
+            "1 problem(s) in the config:\n  family-ipad is assigned to Alice and Bob"
+        );
+    }
+
+    #[test]
+    fn schema_example_is_a_valid_config() {
+        let config: Config = serde_dhall::from_str(DHALL_SCHEMA).parse().unwrap();
+        config.validate().unwrap();
+        assert_eq!(config.router, "192.168.1.1");
+        let names: Vec<_> = config.persons.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, ["Alice", "Bob"]);
+        assert!(config.persons[1].active_hours.is_some());
+    }
+}
@@ -0,0 +1,427 @@
+use std::{
+    io::IsTerminal,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use netrc_rs::{Machine, Netrc};
+use serde::Deserialize;
+use tracing::debug;
+
+pub const PASSWORD_ENV_VAR: &str = "WHOSHOME_ROUTER_PASSWORD";
+pub const USERNAME_ENV_VAR: &str = "WHOSHOME_ROUTER_USERNAME";
+pub const NETRC_ENV_VAR: &str = "WHOSHOME_NETRC";
+
+/// The username from `$WHOSHOME_ROUTER_USERNAME`, if set.
+pub fn env_username() -> Option<String> {
+    std::env::var(USERNAME_ENV_VAR).ok()
+}
+
+pub trait CredentialProvider: Send + Sync {
+    fn name(&self) -> &str;
+    fn password(&self, machine: &str, username: &str) -> Result<Option<String>>;
+
+    /// Why there was no password for `machine`, for the error when none of
+    /// the providers had one.
+    fn not_found(&self, _machine: &str) -> String {
+        self.name().to_string()
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CredentialSource {
+    Cli,
+    Env,
+    Keyring,
+    Netrc,
+    Prompt,
+}
+
+pub const DEFAULT_CREDENTIAL_ORDER: [CredentialSource; 5] = [
+    CredentialSource::Cli,
+    CredentialSource::Env,
+    CredentialSource::Keyring,
+    CredentialSource::Netrc,
+    CredentialSource::Prompt,
+];
+
+pub struct CredentialChain {
+    providers: Vec<Box<dyn CredentialProvider>>,
+}
+
+impl CredentialChain {
+    pub fn new(
+        order: &[CredentialSource],
+        cli_password: Option<String>,
+        netrc_path: PathBuf,
+    ) -> Self {
+        let providers = order
+            .iter()
+            .map(|source| -> Box<dyn CredentialProvider> {
+                match source {
+                    CredentialSource::Cli => Box::new(CliProvider {
+                        password: cli_password.clone(),
+                    }),
+                    CredentialSource::Env => Box::new(EnvProvider),
+                    CredentialSource::Keyring => Box::new(KeyringProvider),
+                    CredentialSource::Netrc => Box::new(NetrcProvider {
+                        path: netrc_path.clone(),
+                    }),
+                    CredentialSource::Prompt => Box::new(PromptProvider),
+                }
+            })
+            .collect();
+        Self { providers }
+    }
+
+    pub fn with_providers(providers: Vec<Box<dyn CredentialProvider>>) -> Self {
+        Self { providers }
+    }
+
+    pub fn get_password(&self, machine: &str, username: &str) -> Result<String> {
+        self.password(machine, username)?.ok_or_else(|| {
+            let tried: Vec<_> = self
+                .providers
+                .iter()
+                .map(|p| p.not_found(machine))
+                .collect();
+            anyhow!(
+                "No password for {machine} found (tried: {})",
+                tried.join(", ")
+            )
+        })
+    }
+}
+
+impl CredentialProvider for CredentialChain {
+    fn name(&self) -> &str {
+        "chain"
+    }
+
+    fn password(&self, machine: &str, username: &str) -> Result<Option<String>> {
+        for provider in &self.providers {
+            let password = provider
+                .password(machine, username)
+                .with_context(|| format!("Failed to get password from {}", provider.name()))?;
+            if password.is_some() {
+                debug!("Using password for {machine} from {}", provider.name());
+                return Ok(password);
+            }
+            debug!("No password for {machine} from {}", provider.name());
+        }
+        Ok(None)
+    }
+}
+
+struct CliProvider {
+    password: Option<String>,
+}
+
+impl CredentialProvider for CliProvider {
+    fn name(&self) -> &str {
+        "cli"
+    }
+
+    fn password(&self, _machine: &str, _username: &str) -> Result<Option<String>> {
+        Ok(self.password.clone())
+    }
+}
+
+struct EnvProvider;
+
+impl CredentialProvider for EnvProvider {
+    fn name(&self) -> &str {
+        "env"
+    }
+
+    fn password(&self, _machine: &str, _username: &str) -> Result<Option<String>> {
+        Ok(std::env::var(PASSWORD_ENV_VAR).ok())
+    }
+
+    fn not_found(&self, _machine: &str) -> String {
+        format!("env ({PASSWORD_ENV_VAR} is not set)")
+    }
+}
+
+struct KeyringProvider;
+
+impl CredentialProvider for KeyringProvider {
+    fn name(&self) -> &str {
+        "keyring"
+    }
+
+    fn password(&self, machine: &str, username: &str) -> Result<Option<String>> {
+        let entry = match keyring::Entry::new(machine, username) {
+            Ok(entry) => entry,
+            Err(e) => {
+                debug!("Keyring unavailable: {e}");
+                return Ok(None);
+            }
+        };
+        match entry.get_password() {
+            Ok(password) => Ok(Some(password)),
+            Err(keyring::Error::NoEntry) => Ok(None),
+            Err(e) => {
+                debug!("Keyring lookup for {machine} failed: {e}");
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// Saves the password in the system keyring, where [`KeyringProvider`]
+/// looks it up.
+pub fn store_password(machine: &str, username: &str, password: &str) -> Result<()> {
+    keyring::Entry::new(machine, username)
+        .and_then(|entry| entry.set_password(password))
+        .with_context(|| format!("Unable to store password for {machine} in the keyring"))
+}
+
+struct NetrcProvider {
+    path: PathBuf,
+}
+
+impl CredentialProvider for NetrcProvider {
+    fn name(&self) -> &str {
+        "netrc"
+    }
+
+    fn password(&self, machine: &str, _username: &str) -> Result<Option<String>> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+        get_password(&self.path, machine)
+    }
+
+    fn not_found(&self, machine: &str) -> String {
+        if self.path.exists() {
+            format!("netrc ({machine} is not in {})", self.path.display())
+        } else {
+            format!("netrc (there is no {})", self.path.display())
+        }
+    }
+}
+
+/// Asks for the password on the terminal, without echoing it. Does nothing
+/// when not run interactively, e.g. from cron.
+struct PromptProvider;
+
+impl CredentialProvider for PromptProvider {
+    fn name(&self) -> &str {
+        "prompt"
+    }
+
+    fn password(&self, machine: &str, username: &str) -> Result<Option<String>> {
+        if !std::io::stdin().is_terminal() {
+            return Ok(None);
+        }
+        let password = rpassword::prompt_password(format!("Password for {username}@{machine}: "))
+            .context("Unable to read password")?;
+        Ok(Some(password))
+    }
+}
+
+/// The `.netrc` from `$WHOSHOME_NETRC` or the config, which then has to
+/// exist, otherwise `~/.netrc`, which doesn't.
+pub fn netrc_path(configured: Option<&Path>) -> Result<PathBuf> {
+    let configured = std::env::var_os(NETRC_ENV_VAR)
+        .map(PathBuf::from)
+        .or_else(|| configured.map(Path::to_path_buf));
+    match configured {
+        Some(path) if !path.exists() => bail!("{} does not exist", path.display()),
+        Some(path) => Ok(path),
+        None => default_netrc_path(),
+    }
+}
+
+fn default_netrc_path() -> Result<PathBuf> {
+    let home = home::home_dir().ok_or_else(|| anyhow!("Unable to find home dir"))?;
+    Ok(home.join(".netrc"))
+}
+
+fn find_machine(netrc_path: &Path, machine: &str) -> Result<Option<Machine>> {
+    let netrc = std::fs::read_to_string(netrc_path).context("Unable to read .netrc")?;
+    let netrc = Netrc::parse(netrc, false).map_err(|e| anyhow!("unable to parse .netrc: {e}"))?;
+    Ok(netrc
+        .machines
+        .into_iter()
+        .find(|m| m.name == Some(machine.into())))
+}
+
+pub fn get_password(netrc_path: &Path, machine: &str) -> Result<Option<String>> {
+    let password = match find_machine(netrc_path, machine)? {
+        Some(m) => m
+            .password
+            .ok_or_else(|| anyhow!("No password for {machine} in .netrc"))?,
+        None => return Ok(None),
+    };
+    Ok(Some(password))
+}
+
+/// The login from the environment, otherwise from `.netrc`, for when the
+/// config doesn't set one. The password is left to the [`CredentialChain`].
+pub fn get_username(netrc_path: &Path, machine: &str) -> Result<String> {
+    if let Some(login) = env_username() {
+        return Ok(login);
+    }
+    if !netrc_path.exists() {
+        bail!(
+            "{USERNAME_ENV_VAR} is not set and there is no {}",
+            netrc_path.display()
+        );
+    }
+    find_machine(netrc_path, machine)?
+        .ok_or_else(|| anyhow!("Could not find {machine} in .netrc"))?
+        .login
+        .ok_or_else(|| anyhow!("No login for {machine} in .netrc"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A `.netrc` in the temp dir, unique to the test.
+    fn netrc(test: &str, contents: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("whoshome-{}-{test}.netrc", std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn username_from_netrc_without_its_password() {
+        let path = netrc(
+            "username",
+            "machine 192.168.1.1 login admin\nmachine 10.0.0.1 login other password secret\n",
+        );
+        let username = get_username(&path, "192.168.1.1");
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(username.unwrap(), "admin");
+    }
+
+    /// Answers with a fixed password, or fails if given an error.
+    struct Fixed {
+        name: &'static str,
+        password: Result<Option<&'static str>, &'static str>,
+    }
+
+    impl CredentialProvider for Fixed {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn password(&self, _machine: &str, _username: &str) -> Result<Option<String>> {
+            match self.password {
+                Ok(password) => Ok(password.map(str::to_string)),
+                Err(e) => Err(anyhow!(e)),
+            }
+        }
+    }
+
+    fn chain(providers: Vec<Fixed>) -> CredentialChain {
+        CredentialChain::with_providers(
+            providers
+                .into_iter()
+                .map(|p| Box::new(p) as Box<dyn CredentialProvider>)
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn first_provider_with_a_password_wins() {
+        let chain = chain(vec![
+            Fixed {
+                name: "first",
+                password: Ok(Some("first")),
+            },
+            Fixed {
+                name: "second",
+                password: Ok(Some("second")),
+            },
+        ]);
+        assert_eq!(chain.get_password("router", "admin").unwrap(), "first");
+    }
+
+    #[test]
+    fn falls_through_providers_without_a_password() {
+        let chain = chain(vec![
+            Fixed {
+                name: "empty",
+                password: Ok(None),
+            },
+            Fixed {
+                name: "second",
+                password: Ok(Some("second")),
+            },
+        ]);
+        assert_eq!(chain.get_password("router", "admin").unwrap(), "second");
+    }
+
+    #[test]
+    fn names_the_providers_tried_when_none_has_a_password() {
+        let chain = chain(vec![
+            Fixed {
+                name: "env",
+                password: Ok(None),
+            },
+            Fixed {
+                name: "netrc",
+                password: Ok(None),
+            },
+        ]);
+        let error = chain.get_password("router", "admin").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "No password for router found (tried: env, netrc)"
+        );
+    }
+
+    #[test]
+    fn failing_provider_stops_the_chain() {
+        let chain = chain(vec![
+            Fixed {
+                name: "keyring",
+                password: Err("locked"),
+            },
+            Fixed {
+                name: "second",
+                password: Ok(Some("second")),
+            },
+        ]);
+        let error = chain.get_password("router", "admin").unwrap_err();
+        assert_eq!(
+            format!("{error:#}"),
+            "Failed to get password from keyring: locked"
+        );
+    }
+
+    #[test]
+    fn says_when_there_is_no_netrc() {
+        let missing = std::env::temp_dir().join("whoshome-no-such.netrc");
+        let chain = CredentialChain::new(&[CredentialSource::Netrc], None, missing.clone());
+        let error = chain.get_password("192.168.1.1", "admin").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "No password for 192.168.1.1 found (tried: netrc (there is no {}))",
+                missing.display()
+            )
+        );
+    }
+
+    #[test]
+    fn says_when_the_netrc_has_no_entry() {
+        let path = netrc("entry", "machine 10.0.0.1 login admin password secret\n");
+        let chain = CredentialChain::new(&[CredentialSource::Netrc], None, path.clone());
+        let error = chain.get_password("192.168.1.1", "admin").unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "No password for 192.168.1.1 found (tried: netrc (192.168.1.1 is not in {}))",
+                path.display()
+            )
+        );
+    }
+}
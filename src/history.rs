@@ -0,0 +1,95 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::cache;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct Entry {
+    home: bool,
+    /// Unix timestamp of when the person arrived or left, `None` if that
+    /// happened before they were first recorded.
+    since: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct History {
+    /// Unix timestamp of the last time presence was recorded.
+    recorded_at: Option<i64>,
+    persons: HashMap<String, Entry>,
+}
+
+/// Whether each person was home the last time whoshome looked, and since
+/// when, kept in the cache dir between runs.
+pub struct PresenceHistory {
+    path: PathBuf,
+    history: History,
+}
+
+impl PresenceHistory {
+    pub fn load() -> Result<Self> {
+        let path = cache::cache_dir()?.join("presence-history.json");
+        let history = load(&path);
+        Ok(Self { path, history })
+    }
+
+    /// Records who is home, noting the time for everybody who arrived or
+    /// left since the last time.
+    pub fn record<'a>(
+        &mut self,
+        presence: impl IntoIterator<Item = (&'a str, bool)>,
+    ) -> Result<()> {
+        let now = Utc::now().timestamp();
+        for (name, home) in presence {
+            let entry = match self.history.persons.get(name) {
+                Some(entry) if entry.home == home => continue,
+                Some(_) => Entry {
+                    home,
+                    since: Some(now),
+                },
+                None => Entry { home, since: None },
+            };
+            self.history.persons.insert(name.to_string(), entry);
+        }
+        self.history.recorded_at = Some(now);
+        self.save()
+    }
+
+    /// When presence was last recorded, `None` if never.
+    pub fn recorded_at(&self) -> Option<DateTime<Utc>> {
+        DateTime::from_timestamp(self.history.recorded_at?, 0)
+    }
+
+    /// Whether the person was home when presence was last recorded.
+    pub fn was_home(&self, name: &str) -> Option<bool> {
+        self.history.persons.get(name).map(|entry| entry.home)
+    }
+
+    /// How long the person has been home or away, `None` if unknown.
+    pub fn duration(&self, name: &str) -> Option<Duration> {
+        let since = self.history.persons.get(name)?.since?;
+        let secs = Utc::now().timestamp().saturating_sub(since);
+        Some(Duration::from_secs(secs.try_into().unwrap_or(0)))
+    }
+
+    fn save(&self) -> Result<()> {
+        std::fs::write(&self.path, serde_json::to_string(&self.history)?)
+            .with_context(|| format!("Unable to write {}", self.path.display()))
+    }
+}
+
+fn load(path: &Path) -> History {
+    let Ok(history) = std::fs::read_to_string(path) else {
+        return History::default();
+    };
+    serde_json::from_str(&history)
+        .map_err(|e| warn!("Ignoring unreadable {}: {e}", path.display()))
+        .unwrap_or_default()
+}
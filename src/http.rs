@@ -0,0 +1,80 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use reqwest::{Certificate, ClientBuilder, NoProxy, Proxy};
+use serde::Deserialize;
+
+use crate::{config::Config, tls};
+
+const DEFAULT_TIMEOUT_SECS: u64 = 10;
+const ALL_PROXY_ENV_VARS: [&str; 2] = ["ALL_PROXY", "all_proxy"];
+const HTTPS_PROXY_ENV_VARS: [&str; 2] = ["HTTPS_PROXY", "https_proxy"];
+
+/// A proxy to reach the routers through, e.g. `http://jumphost:3128` or
+/// `socks5://jumphost:1080`.
+#[derive(Deserialize, Debug)]
+pub struct ProxyConfig {
+    pub url: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+pub fn timeout(config: &Config) -> Duration {
+    Duration::from_secs(config.timeout_secs.unwrap_or(DEFAULT_TIMEOUT_SECS))
+}
+
+/// A client builder with the timeouts and TLS settings from the config applied.
+pub fn client_builder(config: &Config) -> Result<ClientBuilder> {
+    let timeout = timeout(config);
+    let mut builder = reqwest::Client::builder()
+        .timeout(timeout)
+        .connect_timeout(timeout)
+        .danger_accept_invalid_certs(config.accept_invalid_certs);
+    if let Some(ca_cert) = &config.ca_cert {
+        let pem = std::fs::read(ca_cert)
+            .with_context(|| format!("Unable to read CA certificate {}", ca_cert.display()))?;
+        let cert = Certificate::from_pem(&pem)
+            .with_context(|| format!("Failed to parse CA certificate {}", ca_cert.display()))?;
+        builder = builder.add_root_certificate(cert);
+    }
+    if let Some(proxy) = proxy(config)? {
+        builder = builder.proxy(proxy);
+    }
+    if let Some(fingerprint) = &config.cert_fingerprint {
+        let fingerprint = tls::parse_fingerprint(fingerprint)?;
+        builder = builder.use_preconfigured_tls(tls::pinned_config(fingerprint));
+    }
+    Ok(builder)
+}
+
+/// The proxy from the config, otherwise from `ALL_PROXY` except for the hosts
+/// in `NO_PROXY`. reqwest picks up `HTTPS_PROXY` and `HTTP_PROXY` by itself,
+/// but only if no proxy is set here, so `ALL_PROXY` is left out when
+/// `HTTPS_PROXY` is set.
+fn proxy(config: &Config) -> Result<Option<Proxy>> {
+    if let Some(proxy_config) = &config.proxy {
+        let mut proxy = Proxy::all(&proxy_config.url)
+            .with_context(|| format!("Invalid proxy {}", proxy_config.url))?;
+        if let Some(username) = &proxy_config.username {
+            let password = proxy_config.password.as_deref().unwrap_or_default();
+            proxy = proxy.basic_auth(username, password);
+        }
+        return Ok(Some(proxy));
+    }
+    if env_proxy(&HTTPS_PROXY_ENV_VARS).is_some() {
+        return Ok(None);
+    }
+    let Some(url) = env_proxy(&ALL_PROXY_ENV_VARS) else {
+        return Ok(None);
+    };
+    let proxy = Proxy::all(&url)
+        .with_context(|| format!("Invalid proxy {url} in ALL_PROXY"))?
+        .no_proxy(NoProxy::from_env());
+    Ok(Some(proxy))
+}
+
+/// The first of the variables that is set and not empty.
+fn env_proxy(vars: &[&str]) -> Option<String> {
+    vars.iter()
+        .find_map(|var| std::env::var(var).ok().filter(|url| !url.is_empty()))
+}
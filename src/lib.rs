@@ -1,3 +1,29 @@
+//! Find out who is home from the clients connected to the router, and block
+//! or unblock clients. The `whoshome` binary is a thin CLI over this crate.
+
+pub mod cache;
+pub mod changes;
+pub mod clients;
 pub mod config;
+pub mod credentials;
+pub mod history;
+pub mod http;
+pub mod metrics;
+pub mod mock_router;
+pub mod mqtt;
+pub mod openwrt_router;
+pub mod presence;
 pub mod router;
+pub mod schedule;
+pub mod seen;
+pub mod server;
+pub mod systemd;
+#[cfg(test)]
+pub(crate) mod test_util;
+pub mod tls;
 pub mod unifi_dream_router;
+pub mod vendor;
+pub mod webhook;
+
+pub use config::Config;
+pub use router::{Client, Router, RouterError, RouterKind};
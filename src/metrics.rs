@@ -0,0 +1,88 @@
+use std::{convert::Infallible, future::Future, net::SocketAddr, sync::Arc};
+
+use anyhow::{Context, Result};
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server, StatusCode,
+};
+use prometheus::{Encoder, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder};
+use tracing::info;
+
+use crate::{config::Config, presence::is_home, router::Client, server::status_response};
+
+/// Presence gauges exported in the Prometheus text format.
+pub struct PresenceMetrics {
+    registry: Registry,
+    person_home: IntGaugeVec,
+    online_clients: IntGauge,
+}
+
+impl PresenceMetrics {
+    pub fn new() -> Result<Self> {
+        let registry = Registry::new();
+        let person_home = IntGaugeVec::new(
+            Opts::new("whoshome_person_home", "1 if the person is home, 0 if not"),
+            &["name"],
+        )?;
+        let online_clients = IntGauge::new(
+            "whoshome_online_clients",
+            "Number of clients online on the routers",
+        )?;
+        registry.register(Box::new(person_home.clone()))?;
+        registry.register(Box::new(online_clients.clone()))?;
+        Ok(Self {
+            registry,
+            person_home,
+            online_clients,
+        })
+    }
+
+    pub fn update(&self, config: &Config, clients: &[Client]) {
+        for person in &config.persons {
+            self.person_home
+                .with_label_values(&[&person.name])
+                .set(is_home(person, clients).into());
+        }
+        self.online_clients.set(clients.len() as i64);
+    }
+
+    pub fn encode(&self) -> Result<String> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8(buffer)?)
+    }
+}
+
+/// Serves the metrics on `GET /metrics` until `shutdown` completes.
+pub async fn serve(
+    address: SocketAddr,
+    metrics: Arc<PresenceMetrics>,
+    shutdown: impl Future<Output = ()>,
+) -> Result<()> {
+    let make_service = make_service_fn(move |_| {
+        let metrics = metrics.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let metrics = metrics.clone();
+                async move { Ok::<_, Infallible>(handle(&request, &metrics)) }
+            }))
+        }
+    });
+    info!("Serving metrics on http://{address}/metrics");
+    Server::try_bind(&address)
+        .with_context(|| format!("Unable to listen on {address}"))?
+        .serve(make_service)
+        .with_graceful_shutdown(shutdown)
+        .await
+        .context("Metrics server failed")
+}
+
+fn handle(request: &Request<Body>, metrics: &PresenceMetrics) -> Response<Body> {
+    if request.uri().path() != "/metrics" {
+        return status_response(StatusCode::NOT_FOUND);
+    }
+    match metrics.encode() {
+        Ok(text) => Response::new(Body::from(text)),
+        Err(_) => status_response(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
@@ -0,0 +1,123 @@
+use std::sync::Mutex;
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+
+use crate::{
+    config::Config,
+    router::{parse_mac, Client, Router, RouterError},
+};
+
+/// A router that serves fixed client lists and records block/unblock calls,
+/// for tests and for trying the tool without a real router.
+#[derive(Default)]
+pub struct MockRouter {
+    known: Vec<Client>,
+    online: Vec<Client>,
+    blocked: Mutex<Vec<Client>>,
+    unblocked: Mutex<Vec<Client>>,
+}
+
+#[async_trait]
+impl Router for MockRouter {
+    async fn known_clients(&self) -> Result<Vec<Client>, RouterError> {
+        Ok(self.known.clone())
+    }
+
+    async fn online_clients(&self) -> Result<Vec<Client>, RouterError> {
+        Ok(self.online.clone())
+    }
+
+    async fn block_client(&self, client: &Client) -> Result<(), RouterError> {
+        lock(&self.blocked).push(client.clone());
+        Ok(())
+    }
+
+    async fn unblock_client(&self, client: &Client) -> Result<(), RouterError> {
+        lock(&self.unblocked).push(client.clone());
+        Ok(())
+    }
+
+    fn username(&self) -> Result<String> {
+        bail!("The mock router doesn't log in")
+    }
+}
+
+impl MockRouter {
+    pub fn new(known: Vec<Client>, online: Vec<Client>) -> Self {
+        Self {
+            known,
+            online,
+            ..Default::default()
+        }
+    }
+
+    /// All the given clients are both known and online.
+    pub fn with_online(online: Vec<Client>) -> Self {
+        Self::new(online.clone(), online)
+    }
+
+    /// Knows every device in the config and has every other person's devices
+    /// online. Devices configured by MAC keep it.
+    pub fn demo(config: &Config) -> Self {
+        let mut known = Vec::new();
+        let mut online = Vec::new();
+        for (person_index, person) in config.persons.iter().enumerate() {
+            for device in &person.devices {
+                let client = Client {
+                    name: device.name().to_string(),
+                    mac: parse_mac(device.name())
+                        .unwrap_or_else(|| format!("02:00:00:00:00:{:02x}", known.len())),
+                    ..Default::default()
+                };
+                if person_index % 2 == 0 {
+                    online.push(Client {
+                        ip: Some(format!("192.168.1.{}", 100 + known.len())),
+                        ..client.clone()
+                    });
+                }
+                known.push(client);
+            }
+        }
+        Self::new(known, online)
+    }
+
+    pub fn blocked(&self) -> Vec<Client> {
+        lock(&self.blocked).clone()
+    }
+
+    pub fn unblocked(&self) -> Vec<Client> {
+        lock(&self.unblocked).clone()
+    }
+}
+
+fn lock(clients: &Mutex<Vec<Client>>) -> std::sync::MutexGuard<'_, Vec<Client>> {
+    clients.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn demo_keeps_the_configured_macs() {
+        let config: Config = toml::from_str(
+            r#"
+            router = "192.168.1.1"
+            [[persons]]
+            name = "Alice"
+            devices = ["Alices-iPhone", "AA-BB-CC-00-00-01"]
+            "#,
+        )
+        .unwrap();
+        let router = MockRouter::demo(&config);
+        let macs: Vec<_> = router
+            .known_clients()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|c| c.mac)
+            .collect();
+        assert_eq!(macs, ["02:00:00:00:00:00", "aa:bb:cc:00:00:01"]);
+    }
+}
@@ -0,0 +1,124 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use rumqttc::{AsyncClient, Event, EventLoop, LastWill, MqttOptions, Outgoing, Packet, QoS};
+use serde::Deserialize;
+use tracing::{info, warn};
+
+const DEFAULT_PORT: u16 = 1883;
+const DEFAULT_TOPIC_PREFIX: &str = "whoshome";
+const DEFAULT_CLIENT_ID: &str = "whoshome";
+const ONLINE: &str = "online";
+const OFFLINE: &str = "offline";
+
+#[derive(Deserialize, Debug)]
+pub struct MqttConfig {
+    pub host: String,
+    pub port: Option<u16>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub topic_prefix: Option<String>,
+    pub client_id: Option<String>,
+}
+
+/// Publishes presence as retained `home`/`away` messages on
+/// `<prefix>/person/<name>`, with `online`/`offline` on `<prefix>/status`
+/// as availability. The broker publishes `offline` itself if the
+/// connection is lost.
+pub struct MqttPublisher {
+    client: AsyncClient,
+    prefix: String,
+}
+
+impl MqttPublisher {
+    /// The event loop must be driven with [`MqttPublisher::run`] for
+    /// anything to be sent.
+    pub fn new(config: &MqttConfig) -> (Self, EventLoop) {
+        let prefix = config
+            .topic_prefix
+            .clone()
+            .unwrap_or_else(|| DEFAULT_TOPIC_PREFIX.to_string());
+        let mut options = MqttOptions::new(
+            config.client_id.as_deref().unwrap_or(DEFAULT_CLIENT_ID),
+            &config.host,
+            config.port.unwrap_or(DEFAULT_PORT),
+        );
+        options.set_keep_alive(Duration::from_secs(30));
+        options.set_last_will(LastWill::new(
+            status_topic(&prefix),
+            OFFLINE,
+            QoS::AtLeastOnce,
+            true,
+        ));
+        if let Some(username) = &config.username {
+            options.set_credentials(username, config.password.clone().unwrap_or_default());
+        }
+        let (client, event_loop) = AsyncClient::new(options, 16);
+        (Self { client, prefix }, event_loop)
+    }
+
+    /// Drives the connection, reconnecting on errors and announcing
+    /// availability each time the broker accepts the connection.
+    pub async fn run(&self, event_loop: &mut EventLoop) {
+        loop {
+            match event_loop.poll().await {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    info!("Connected to MQTT broker");
+                    // Can't wait for room in the queue here, since this loop
+                    // is what empties it.
+                    if let Err(e) = self.client.try_publish(
+                        status_topic(&self.prefix),
+                        QoS::AtLeastOnce,
+                        true,
+                        ONLINE,
+                    ) {
+                        warn!("Failed to publish availability: {e}");
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    warn!("MQTT connection failed: {e}");
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                }
+            }
+        }
+    }
+
+    pub async fn publish_person(&self, name: &str, home: bool) -> Result<()> {
+        let payload = if home { "home" } else { "away" };
+        self.publish(format!("{}/person/{name}", self.prefix), payload)
+            .await
+    }
+
+    /// Marks the bridge as offline and disconnects cleanly, giving up after
+    /// a few seconds if the broker can't be reached.
+    pub async fn shutdown(&self, event_loop: &mut EventLoop) -> Result<()> {
+        self.publish(status_topic(&self.prefix), OFFLINE).await?;
+        self.client
+            .disconnect()
+            .await
+            .context("Failed to disconnect from MQTT broker")?;
+        let flush = async {
+            loop {
+                match event_loop.poll().await {
+                    Ok(Event::Outgoing(Outgoing::Disconnect)) | Err(_) => break,
+                    Ok(_) => {}
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), flush)
+            .await
+            .context("Timed out disconnecting from MQTT broker")
+    }
+
+    async fn publish(&self, topic: String, payload: &str) -> Result<()> {
+        self.client
+            .publish(&topic, QoS::AtLeastOnce, true, payload)
+            .await
+            .with_context(|| format!("Failed to publish to {topic}"))
+    }
+}
+
+fn status_topic(prefix: &str) -> String {
+    format!("{prefix}/status")
+}
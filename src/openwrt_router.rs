@@ -0,0 +1,414 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use early::Early;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::sync::Mutex;
+use tracing::{info, trace};
+
+use crate::{
+    config::Config,
+    credentials::{self, CredentialChain},
+    http,
+    router::{normalize_mac, Client, RouterError},
+    vendor,
+};
+
+const NULL_SESSION: &str = "00000000000000000000000000000000";
+const DEFAULT_USERNAME: &str = "root";
+const UBUS_STATUS_OK: i64 = 0;
+const UBUS_STATUS_PERMISSION_DENIED: i64 = 6;
+const JSONRPC_ACCESS_DENIED: i64 = -32002;
+const BLOCK_SECTION_PREFIX: &str = "whoshome_block_";
+
+/// A router running OpenWrt, talked to through the ubus JSON-RPC interface
+/// that LuCI exposes on `/ubus`. Blocking is done with firewall rules
+/// matching the client's MAC.
+pub struct OpenWrtRouter {
+    http_client: reqwest::Client,
+    ubus_url: String,
+    hostname: String,
+    username: String,
+    session: Mutex<Option<String>>,
+    credentials: Arc<CredentialChain>,
+    timeout: Duration,
+}
+
+#[async_trait]
+impl crate::router::Router for OpenWrtRouter {
+    async fn known_clients(&self) -> Result<Vec<Client>, RouterError> {
+        info!(
+            "Getting list of known clients from OpenWrtRouter: {}",
+            self.hostname
+        );
+        let hints = self.host_hints().await?;
+        let blocked = self.blocked_macs().await?;
+        Ok(hints
+            .into_iter()
+            .map(|(mac, hint)| {
+                let mac = normalize_mac(&mac);
+                Client {
+                    name: hint.name(&mac),
+                    blocked: blocked.contains(&mac),
+                    vendor: vendor::vendor(&mac),
+                    mac,
+                    ..Default::default()
+                }
+            })
+            .collect())
+    }
+
+    async fn online_clients(&self) -> Result<Vec<Client>, RouterError> {
+        info!(
+            "Getting list of connected clients from OpenWrtRouter: {}",
+            self.hostname
+        );
+        Ok(self.associated_clients().await?)
+    }
+
+    async fn block_client(&self, client: &Client) -> Result<(), RouterError> {
+        info!("Blocking {}", client.name);
+        let section = block_section(&client.mac);
+        if let Err(e) = self
+            .call(
+                "uci",
+                "delete",
+                json!({ "config": "firewall", "section": section }),
+            )
+            .await
+        {
+            trace!("No existing block rule {section}: {e}");
+        }
+        self.call(
+            "uci",
+            "add",
+            json!({
+                "config": "firewall",
+                "type": "rule",
+                "name": section,
+                "values": {
+                    "name": format!("whoshome block {}", client.name),
+                    "src": "lan",
+                    "dest": "wan",
+                    "src_mac": client.mac,
+                    "target": "REJECT",
+                },
+            }),
+        )
+        .await?;
+        Ok(self.apply_firewall().await?)
+    }
+
+    async fn unblock_client(&self, client: &Client) -> Result<(), RouterError> {
+        info!("Unblocking {}", client.name);
+        self.call(
+            "uci",
+            "delete",
+            json!({ "config": "firewall", "section": block_section(&client.mac) }),
+        )
+        .await?;
+        Ok(self.apply_firewall().await?)
+    }
+
+    fn username(&self) -> Result<String> {
+        Ok(self.username.clone())
+    }
+}
+
+impl OpenWrtRouter {
+    pub fn new(hostname: &str, config: &Config, credentials: Arc<CredentialChain>) -> Result<Self> {
+        let mut router = Early::new("https", hostname);
+        if let Some(port) = config.port {
+            router = router.port(port);
+        }
+        let http_client = http::client_builder(config)?
+            .build()
+            .context("Failed to build http client")?;
+
+        Ok(OpenWrtRouter {
+            http_client,
+            ubus_url: router.path("ubus").build(),
+            hostname: hostname.to_owned(),
+            username: config
+                .username
+                .clone()
+                .or_else(credentials::env_username)
+                .unwrap_or_else(|| DEFAULT_USERNAME.to_string()),
+            session: Mutex::new(None),
+            credentials,
+            timeout: http::timeout(config),
+        })
+    }
+
+    /// The wireless clients associated with any of the router's radios.
+    async fn associated_clients(&self) -> Result<Vec<Client>> {
+        let hints = self.host_hints().await?;
+        let devices: WirelessDevices =
+            serde_json::from_value(self.call("iwinfo", "devices", json!({})).await?)?;
+        let mut clients: Vec<Client> = Vec::new();
+        for device in devices.devices {
+            let associated: AssocList = serde_json::from_value(
+                self.call("iwinfo", "assoclist", json!({ "device": device }))
+                    .await?,
+            )?;
+            for station in associated.results {
+                let mac = normalize_mac(&station.mac);
+                if clients.iter().any(|c| c.mac == mac) {
+                    continue;
+                }
+                let hint = hints
+                    .iter()
+                    .find(|(m, _)| normalize_mac(m) == mac)
+                    .map(|(_, hint)| hint);
+                clients.push(Client {
+                    name: match hint {
+                        Some(hint) => hint.name(&mac),
+                        None => vendor::unnamed_client_name(&mac),
+                    },
+                    vendor: vendor::vendor(&mac),
+                    mac,
+                    ip: hint.and_then(|h| h.ipaddrs.first().cloned()),
+                    wired: Some(false),
+                    signal: station.signal,
+                    ..Default::default()
+                });
+            }
+        }
+        Ok(clients)
+    }
+
+    async fn host_hints(&self) -> Result<Vec<(String, HostHint)>> {
+        let hints: std::collections::HashMap<String, HostHint> =
+            serde_json::from_value(self.call("luci-rpc", "getHostHints", json!({})).await?)?;
+        Ok(hints.into_iter().collect())
+    }
+
+    /// The MACs that have a block rule added by [`Router::block_client`].
+    ///
+    /// [`Router::block_client`]: crate::router::Router::block_client
+    async fn blocked_macs(&self) -> Result<Vec<String>> {
+        let firewall: UciConfig = serde_json::from_value(
+            self.call("uci", "get", json!({ "config": "firewall" }))
+                .await?,
+        )?;
+        Ok(firewall
+            .values
+            .into_iter()
+            .filter(|(section, _)| section.starts_with(BLOCK_SECTION_PREFIX))
+            .filter_map(|(_, values)| values.get("src_mac")?.as_str().map(normalize_mac))
+            .collect())
+    }
+
+    async fn apply_firewall(&self) -> Result<()> {
+        self.call("uci", "commit", json!({ "config": "firewall" }))
+            .await?;
+        self.call(
+            "rc",
+            "init",
+            json!({ "name": "firewall", "action": "reload" }),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn call(&self, object: &str, method: &str, args: Value) -> Result<Value> {
+        let session = self.session().await?;
+        match self
+            .raw_call(&session, object, method, args.clone())
+            .await?
+        {
+            Some(value) => Ok(value),
+            None => {
+                trace!("Access denied, logging in again on: {}", self.hostname);
+                *self.session.lock().await = None;
+                let session = self.session().await?;
+                self.raw_call(&session, object, method, args)
+                    .await?
+                    .ok_or_else(|| {
+                        anyhow!(
+                            "Access denied calling {object}.{method} on {}",
+                            self.hostname
+                        )
+                    })
+            }
+        }
+    }
+
+    async fn session(&self) -> Result<String> {
+        let mut session = self.session.lock().await;
+        if let Some(session) = session.as_ref() {
+            return Ok(session.clone());
+        }
+        let new_session = self.login().await.context("Failed to login on router")?;
+        *session = Some(new_session.clone());
+        Ok(new_session)
+    }
+
+    async fn login(&self) -> Result<String> {
+        let password = self
+            .credentials
+            .get_password(&self.hostname, &self.username)
+            .with_context(|| format!("Failed to get password for {}", self.hostname))?;
+        let result = self
+            .raw_call(
+                NULL_SESSION,
+                "session",
+                "login",
+                json!({ "username": self.username, "password": password }),
+            )
+            .await?
+            .ok_or_else(|| anyhow!("Login failed on {}", self.hostname))?;
+        let session = result
+            .get("ubus_rpc_session")
+            .and_then(Value::as_str)
+            .ok_or_else(|| anyhow!("No session in login response from {}", self.hostname))?;
+        Ok(session.to_owned())
+    }
+
+    /// Returns `None` when the session isn't allowed to make the call.
+    async fn raw_call(
+        &self,
+        session: &str,
+        object: &str,
+        method: &str,
+        args: Value,
+    ) -> Result<Option<Value>> {
+        let request = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "call",
+            "params": [session, object, method, args],
+        });
+        let response: RpcResponse = self
+            .http_client
+            .post(&self.ubus_url)
+            .json(&request)
+            .send()
+            .await
+            .map_err(|e| {
+                RouterError::from_request(&self.hostname, &self.ubus_url, self.timeout, e)
+            })?
+            .error_for_status()?
+            .json()
+            .await
+            .map_err(|e| {
+                RouterError::from_request(&self.hostname, &self.ubus_url, self.timeout, e)
+            })?;
+        if let Some(error) = response.error {
+            if error.code == JSONRPC_ACCESS_DENIED {
+                return Ok(None);
+            }
+            bail!("ubus call {object}.{method} failed: {}", error.message);
+        }
+        let result = response
+            .result
+            .ok_or_else(|| anyhow!("ubus call {object}.{method} returned no result"))?;
+        match result.first().and_then(Value::as_i64) {
+            Some(UBUS_STATUS_OK) => Ok(Some(result.get(1).cloned().unwrap_or(Value::Null))),
+            Some(UBUS_STATUS_PERMISSION_DENIED) => Ok(None),
+            Some(status) => bail!("ubus call {object}.{method} returned status {status}"),
+            None => bail!("ubus call {object}.{method} returned a malformed result"),
+        }
+    }
+}
+
+fn block_section(mac: &str) -> String {
+    format!(
+        "{BLOCK_SECTION_PREFIX}{}",
+        normalize_mac(mac).replace(':', "")
+    )
+}
+
+#[derive(Deserialize, Debug)]
+struct RpcResponse {
+    result: Option<Vec<Value>>,
+    error: Option<RpcError>,
+}
+
+#[derive(Deserialize, Debug)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct HostHint {
+    name: Option<String>,
+    #[serde(default)]
+    ipaddrs: Vec<String>,
+}
+
+impl HostHint {
+    fn name(&self, mac: &str) -> String {
+        self.name
+            .clone()
+            .unwrap_or_else(|| vendor::unnamed_client_name(mac))
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct UciConfig {
+    values: std::collections::HashMap<String, Value>,
+}
+
+#[derive(Deserialize, Debug)]
+struct WirelessDevices {
+    devices: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct AssocList {
+    results: Vec<Station>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Station {
+    mac: String,
+    signal: Option<i32>,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::{credentials::CredentialSource, router::Router};
+
+    #[tokio::test]
+    async fn unreachable_router_is_reported_as_such() {
+        // A port that nothing listens on any more
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let config: Config = toml::from_str(&format!(
+            "router = \"127.0.0.1\"\nport = {port}\npersons = []"
+        ))
+        .unwrap();
+        let credentials = Arc::new(CredentialChain::new(
+            &[CredentialSource::Cli],
+            Some("secret".to_string()),
+            PathBuf::new(),
+        ));
+        let router = OpenWrtRouter::new("127.0.0.1", &config, credentials).unwrap();
+        let error = router.online_clients().await.unwrap_err();
+        assert!(error.is_unreachable(), "{error:?}");
+    }
+
+    #[test]
+    fn logs_in_as_root_unless_configured() {
+        let credentials = Arc::new(CredentialChain::new(&[], None, PathBuf::new()));
+        let config: Config = toml::from_str("router = \"openwrt\"\npersons = []").unwrap();
+        let router = OpenWrtRouter::new("openwrt", &config, credentials.clone()).unwrap();
+        if credentials::env_username().is_none() {
+            assert_eq!(router.username().unwrap(), "root");
+        }
+        let config: Config =
+            toml::from_str("router = \"openwrt\"\nusername = \"admin\"\npersons = []").unwrap();
+        let router = OpenWrtRouter::new("openwrt", &config, credentials).unwrap();
+        assert_eq!(router.username().unwrap(), "admin");
+    }
+}
@@ -0,0 +1,420 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, NaiveTime, Utc};
+use serde::Serialize;
+
+use crate::{
+    config::{Config, Device, Match, Person},
+    router::{normalize_mac, parse_mac, Client},
+    schedule::TimeWindow,
+};
+
+pub fn names_match(a: &str, b: &str) -> bool {
+    a.trim().eq_ignore_ascii_case(b.trim())
+}
+
+/// A device entry that looks like a MAC address is matched against the
+/// client's MAC, anything else against the client's name.
+pub fn device_matches(device: &str, client: &Client) -> bool {
+    match parse_mac(device) {
+        Some(mac) => normalize_mac(&client.mac) == mac,
+        None => names_match(&client.name, device),
+    }
+}
+
+/// Whether two device entries (names or MACs) refer to the same device.
+pub fn devices_match(a: &str, b: &str) -> bool {
+    match (parse_mac(a), parse_mac(b)) {
+        (Some(a), Some(b)) => a == b,
+        (None, None) => names_match(a, b),
+        _ => false,
+    }
+}
+
+/// The persons that list `device` among their devices.
+pub fn owners<'c>(config: &'c Config, device: &str) -> Vec<&'c Person> {
+    config
+        .persons
+        .iter()
+        .filter(|p| {
+            p.devices
+                .iter()
+                .flat_map(Device::names)
+                .any(|d| devices_match(d, device))
+        })
+        .collect()
+}
+
+/// The clients that aren't any person's device, e.g. guests.
+pub fn unassigned<'c>(config: &Config, clients: &'c [Client]) -> Vec<&'c Client> {
+    clients
+        .iter()
+        .filter(|c| {
+            !config
+                .persons
+                .iter()
+                .flat_map(|p| &p.devices)
+                .flat_map(Device::names)
+                .any(|d| device_matches(d, c))
+        })
+        .collect()
+}
+
+pub fn is_home(person: &Person, clients: &[Client]) -> bool {
+    is_home_at(person, clients, chrono::Local::now().time())
+}
+
+/// Like [`is_home`], ignoring devices outside their active hours at `time`.
+pub fn is_home_at(person: &Person, clients: &[Client], time: NaiveTime) -> bool {
+    matched_client_at(person, clients, time).is_some()
+}
+
+/// The online client that makes the person count as home, if any.
+pub fn matched_client<'c>(person: &Person, clients: &'c [Client]) -> Option<&'c Client> {
+    matched_client_at(person, clients, chrono::Local::now().time())
+}
+
+fn matched_client_at<'c>(
+    person: &Person,
+    clients: &'c [Client],
+    time: NaiveTime,
+) -> Option<&'c Client> {
+    let active = |window: Option<&TimeWindow>| window.is_none_or(|w| w.contains(time));
+    if !active(person.active_hours.as_ref()) {
+        return None;
+    }
+    let mut devices = person.devices.iter().filter(|d| active(d.active_hours()));
+    let online = |device: &Device| {
+        device
+            .names()
+            .find_map(|d| clients.iter().find(|c| device_matches(d, c)))
+    };
+    match &person.match_mode {
+        Match::Any => devices.find_map(online),
+        Match::All => {
+            let matched: Option<Vec<_>> = devices.map(online).collect();
+            matched?.into_iter().next()
+        }
+        Match::Primary(primary) => devices
+            .filter(|d| d.names().any(|n| devices_match(n, primary)))
+            .find_map(online),
+    }
+}
+
+pub fn persons_home<'c>(config: &'c Config, clients: &[Client]) -> Vec<&'c str> {
+    persons_home_at(config, clients, chrono::Local::now().time())
+}
+
+/// The names of the persons home at `time` given the online clients. Does
+/// no I/O and doesn't look at the clock.
+pub fn persons_home_at<'c>(
+    config: &'c Config,
+    clients: &[Client],
+    time: NaiveTime,
+) -> Vec<&'c str> {
+    config
+        .persons
+        .iter()
+        .filter(|p| is_home_at(p, clients, time))
+        .map(|p| p.name.as_str())
+        .collect()
+}
+
+/// Delays departures until a person has been missing for `away_after`
+/// polls in a row, so a phone briefly dropping off Wi-Fi while asleep isn't
+/// reported as leaving. Arrivals count right away.
+pub struct Debounce<'c> {
+    away_after: u32,
+    home: Vec<&'c str>,
+    misses: HashMap<&'c str, u32>,
+}
+
+impl<'c> Debounce<'c> {
+    pub fn new(away_after: u32, home: Vec<&'c str>) -> Self {
+        Self {
+            away_after: away_after.max(1),
+            home,
+            misses: HashMap::new(),
+        }
+    }
+
+    /// Takes who was seen home in the latest poll and returns who should be
+    /// considered home.
+    pub fn update(&mut self, seen_home: Vec<&'c str>) -> Vec<&'c str> {
+        let mut home = seen_home;
+        for name in &self.home {
+            if home.contains(name) {
+                self.misses.remove(name);
+                continue;
+            }
+            let misses = self.misses.entry(name).or_insert(0);
+            *misses += 1;
+            if *misses < self.away_after {
+                home.push(name);
+            } else {
+                self.misses.remove(name);
+            }
+        }
+        self.home = home.clone();
+        home
+    }
+}
+
+/// Whether a person is home, as reported in JSON output.
+#[derive(Serialize, Debug, Clone)]
+pub struct PersonStatus {
+    pub name: String,
+    pub home: bool,
+    /// Seconds since the person arrived or left, `None` if unknown.
+    pub duration_secs: Option<u64>,
+    /// The device that made the person count as home.
+    pub matched_device: Option<MatchedDevice>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct MatchedDevice {
+    pub name: String,
+    pub mac: String,
+}
+
+/// Everybody's status with how many are home, as reported in JSON output
+/// and by the server.
+#[derive(Serialize, Debug)]
+pub struct WhosHome<'a> {
+    pub persons: &'a [PersonStatus],
+    pub home: usize,
+    pub total: usize,
+    /// When the presence was recorded if the routers couldn't be reached,
+    /// `None` for live presence.
+    pub stale_as_of: Option<String>,
+}
+
+impl<'a> WhosHome<'a> {
+    pub fn new(persons: &'a [PersonStatus], stale_as_of: Option<DateTime<Utc>>) -> Self {
+        Self {
+            persons,
+            home: persons.iter().filter(|s| s.home).count(),
+            total: persons.len(),
+            stale_as_of: stale_as_of.map(|at| at.to_rfc3339()),
+        }
+    }
+}
+
+pub fn statuses(config: &Config, clients: &[Client]) -> Vec<PersonStatus> {
+    config
+        .persons
+        .iter()
+        .map(|p| {
+            let matched = matched_client(p, clients);
+            PersonStatus {
+                name: p.name.clone(),
+                home: matched.is_some(),
+                duration_secs: None,
+                matched_device: matched.map(|c| MatchedDevice {
+                    name: c.name.clone(),
+                    mac: c.mac.clone(),
+                }),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{client, config};
+
+    const NOON: NaiveTime = NaiveTime::from_hms_opt(12, 0, 0).unwrap();
+
+    fn online() -> Vec<Client> {
+        vec![
+            client("Alices-iPhone", "aa:bb:cc:00:00:01"),
+            client("family-ipad", "aa:bb:cc:00:00:02"),
+        ]
+    }
+
+    #[test]
+    fn any_device_online_is_enough() {
+        let config = config(
+            r#"
+            [[persons]]
+            name = "Alice"
+            devices = ["alice-laptop", "Alices-iPhone"]
+            "#,
+        );
+        assert_eq!(persons_home_at(&config, &online(), NOON), ["Alice"]);
+    }
+
+    #[test]
+    fn all_requires_every_device() {
+        let config = config(
+            r#"
+            [[persons]]
+            name = "Alice"
+            devices = ["Alices-iPhone", "alice-laptop"]
+            match = "all"
+
+            [[persons]]
+            name = "Bob"
+            devices = ["Alices-iPhone", "family-ipad"]
+            match = "all"
+            "#,
+        );
+        // Shared devices aren't allowed by validate, but presence doesn't care
+        assert_eq!(persons_home_at(&config, &online(), NOON), ["Bob"]);
+    }
+
+    #[test]
+    fn primary_ignores_the_other_devices() {
+        let config = config(
+            r#"
+            [[persons]]
+            name = "Alice"
+            devices = ["Alices-iPhone", "alice-laptop"]
+            match = { primary = "alice-laptop" }
+
+            [[persons]]
+            name = "Bob"
+            devices = ["bobs-pixel", "Alices-iPhone"]
+            match = { primary = "alices-iphone" }
+            "#,
+        );
+        assert_eq!(persons_home_at(&config, &online(), NOON), ["Bob"]);
+    }
+
+    #[test]
+    fn matched_client_follows_the_match_mode() {
+        let config = config(
+            r#"
+            [[persons]]
+            name = "Any"
+            devices = ["alice-laptop", "family-ipad", "Alices-iPhone"]
+
+            [[persons]]
+            name = "Primary"
+            devices = ["family-ipad", "Alices-iPhone"]
+            match = { primary = "Alices-iPhone" }
+
+            [[persons]]
+            name = "All"
+            devices = ["Alices-iPhone", "family-ipad"]
+            match = "all"
+            "#,
+        );
+        let clients = online();
+        let matched = |i: usize| {
+            matched_client_at(&config.persons[i], &clients, NOON).map(|c| c.name.as_str())
+        };
+        assert_eq!(matched(0), Some("family-ipad"));
+        assert_eq!(matched(1), Some("Alices-iPhone"));
+        assert_eq!(matched(2), Some("Alices-iPhone"));
+    }
+
+    #[test]
+    fn all_skips_devices_outside_their_active_hours() {
+        let config = config(
+            r#"
+            [[persons]]
+            name = "Alice"
+            match = "all"
+            devices = [
+                "Alices-iPhone",
+                { name = "alice-laptop", active_hours = { from = "18:00", to = "23:00" } },
+            ]
+            "#,
+        );
+        assert_eq!(persons_home_at(&config, &online(), NOON), ["Alice"]);
+        let evening = NaiveTime::from_hms_opt(19, 0, 0).unwrap();
+        assert!(persons_home_at(&config, &online(), evening).is_empty());
+    }
+
+    #[test]
+    fn names_match_ignoring_case_and_surrounding_space() {
+        let config = config(
+            r#"
+            [[persons]]
+            name = "Alice"
+            devices = ["  alices-IPHONE "]
+            "#,
+        );
+        assert_eq!(persons_home_at(&config, &online(), NOON), ["Alice"]);
+        assert!(names_match("Alice", "aLICE"));
+        assert!(names_match("Alice", "alice "));
+        assert!(names_match("Alices-iPhone\t", " ALICES-IPHONE"));
+        assert!(!names_match("Alice", "Alic"));
+        assert!(!names_match("Alice", "Al ice"));
+    }
+
+    #[test]
+    fn macs_match_in_any_notation() {
+        for mac in ["AA:BB:CC:00:00:02", "aa-bb-cc-00-00-02", "aabb.cc00.0002"] {
+            let config = config(&format!(
+                "[[persons]]\nname = \"Alice\"\ndevices = [\"{mac}\"]"
+            ));
+            assert_eq!(
+                persons_home_at(&config, &online(), NOON),
+                ["Alice"],
+                "{mac}"
+            );
+        }
+    }
+
+    #[test]
+    fn nobody_home_without_matching_clients() {
+        let config = config(
+            r#"
+            [[persons]]
+            name = "Bob"
+            devices = ["bobs-pixel", "aa:bb:cc:00:00:03"]
+            "#,
+        );
+        assert!(persons_home_at(&config, &online(), NOON).is_empty());
+    }
+
+    #[test]
+    fn devices_outside_active_hours_are_ignored() {
+        let config = config(
+            r#"
+            [[persons]]
+            name = "Alice"
+            devices = ["Alices-iPhone"]
+            active_hours = { from = "18:00", to = "08:00" }
+            "#,
+        );
+        assert!(persons_home_at(&config, &online(), NOON).is_empty());
+        let night = NaiveTime::from_hms_opt(23, 0, 0).unwrap();
+        assert_eq!(persons_home_at(&config, &online(), night), ["Alice"]);
+    }
+
+    #[test]
+    fn departure_is_held_for_away_after_minus_one_polls() {
+        let mut debounce = Debounce::new(3, vec!["Alice", "Bob"]);
+        assert_eq!(debounce.update(vec!["Bob"]), ["Bob", "Alice"]);
+        assert_eq!(debounce.update(vec!["Bob"]), ["Bob", "Alice"]);
+        assert_eq!(debounce.update(vec!["Bob"]), ["Bob"]);
+        assert_eq!(debounce.update(vec!["Bob"]), ["Bob"]);
+    }
+
+    #[test]
+    fn coming_back_resets_the_misses() {
+        let mut debounce = Debounce::new(2, vec!["Alice"]);
+        assert_eq!(debounce.update(vec![]), ["Alice"]);
+        assert_eq!(debounce.update(vec!["Alice"]), ["Alice"]);
+        assert_eq!(debounce.update(vec![]), ["Alice"]);
+        assert!(debounce.update(vec![]).is_empty());
+    }
+
+    #[test]
+    fn away_after_zero_or_one_leaves_immediately() {
+        for away_after in [0, 1] {
+            let mut debounce = Debounce::new(away_after, vec!["Alice"]);
+            assert!(debounce.update(vec![]).is_empty(), "{away_after}");
+        }
+    }
+
+    #[test]
+    fn arrival_counts_on_the_first_poll() {
+        let mut debounce = Debounce::new(3, vec![]);
+        assert_eq!(debounce.update(vec!["Alice"]), ["Alice"]);
+    }
+}
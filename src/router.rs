@@ -1,16 +1,233 @@
+use std::{collections::HashSet, sync::Arc, time::Duration};
+
 use anyhow::Result;
 use async_trait::async_trait;
+use serde::Deserialize;
+
+use crate::{
+    config::Config, credentials::CredentialChain, mock_router::MockRouter,
+    openwrt_router::OpenWrtRouter, unifi_dream_router::UnifiDreamRouter,
+};
 
 #[async_trait]
-pub trait Router {
-    async fn known_clients(&self) -> Result<Vec<Client>>;
-    async fn online_clients(&self) -> Result<Vec<Client>>;
-    async fn block_client(&self, client: &Client) -> Result<()>;
-    async fn unblock_client(&self, client: &Client) -> Result<()>;
+pub trait Router: Send + Sync {
+    async fn known_clients(&self) -> Result<Vec<Client>, RouterError>;
+    async fn online_clients(&self) -> Result<Vec<Client>, RouterError>;
+    async fn block_client(&self, client: &Client) -> Result<(), RouterError>;
+    async fn unblock_client(&self, client: &Client) -> Result<(), RouterError>;
+    /// The username the router logs in with, e.g. to store its password.
+    fn username(&self) -> Result<String>;
+}
+
+/// The ways talking to a router can fail, for callers that want to react to
+/// some of them rather than just report them.
+#[derive(Debug, thiserror::Error)]
+pub enum RouterError {
+    #[error("Unable to reach router {host}")]
+    Unreachable {
+        host: String,
+        #[source]
+        source: reqwest::Error,
+    },
+    #[error("Request to {url} timed out after {timeout:?}; is the router reachable?")]
+    TimedOut {
+        url: String,
+        timeout: Duration,
+        #[source]
+        source: reqwest::Error,
+    },
+    #[error("Login on router {host} failed: {reason}")]
+    AuthFailed { host: String, reason: String },
+    #[error("Router {host} returned an error: {message}")]
+    Api { host: String, message: String },
+    #[error("Could not find client named '{name}'{}", did_you_mean(.suggestions))]
+    ClientNotFound {
+        name: String,
+        /// Names of similar clients, closest first.
+        suggestions: Vec<String>,
+    },
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+impl From<anyhow::Error> for RouterError {
+    /// Unwraps a `RouterError` that was passed on as an `anyhow::Error`, so
+    /// callers can still react to it.
+    fn from(error: anyhow::Error) -> Self {
+        error.downcast().unwrap_or_else(RouterError::Other)
+    }
+}
+
+impl RouterError {
+    /// Whether the router couldn't be reached at all, e.g. while it reboots,
+    /// rather than rejecting the request.
+    pub fn is_unreachable(&self) -> bool {
+        matches!(
+            self,
+            RouterError::Unreachable { .. } | RouterError::TimedOut { .. }
+        )
+    }
+
+    /// Tells failing to reach the router at `host` apart from other errors of
+    /// a request to `url` made with the given `timeout`.
+    pub(crate) fn from_request(
+        host: &str,
+        url: &str,
+        timeout: Duration,
+        error: reqwest::Error,
+    ) -> RouterError {
+        if error.is_timeout() {
+            RouterError::TimedOut {
+                url: url.to_owned(),
+                timeout,
+                source: error,
+            }
+        } else if error.is_connect() {
+            RouterError::Unreachable {
+                host: host.to_owned(),
+                source: error,
+            }
+        } else {
+            RouterError::Http(error)
+        }
+    }
+}
+
+fn did_you_mean(suggestions: &[String]) -> String {
+    if suggestions.is_empty() {
+        return String::new();
+    }
+    let quoted: Vec<_> = suggestions.iter().map(|s| format!("'{s}'")).collect();
+    format!("; did you mean {}?", quoted.join(", "))
+}
+
+/// Which kind of router the config talks to.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+#[value(rename_all = "lowercase")]
+pub enum RouterKind {
+    #[default]
+    Unifi,
+    OpenWrt,
+    /// A fake router built from the config, for trying the tool out.
+    Mock,
 }
 
-#[derive(Debug, Clone)]
+/// One router of the given kind for each of the configured hosts.
+pub fn create_routers(
+    config: &Config,
+    kind: RouterKind,
+    credentials: Arc<CredentialChain>,
+) -> Result<Vec<Box<dyn Router>>> {
+    config
+        .router_hosts()
+        .map(|host| -> Result<Box<dyn Router>> {
+            let router: Box<dyn Router> = match kind {
+                RouterKind::Unifi => {
+                    Box::new(UnifiDreamRouter::new(host, config, credentials.clone())?)
+                }
+                RouterKind::OpenWrt => {
+                    Box::new(OpenWrtRouter::new(host, config, credentials.clone())?)
+                }
+                RouterKind::Mock => Box::new(MockRouter::demo(config)),
+            };
+            Ok(router)
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct Client {
     pub name: String,
     pub mac: String,
+    pub ip: Option<String>,
+    /// Seconds the client has been connected.
+    pub uptime: Option<u64>,
+    pub wired: Option<bool>,
+    /// Unix timestamp of when the router last saw the client.
+    pub last_seen: Option<i64>,
+    /// Signal strength in dBm, only for wireless clients.
+    pub signal: Option<i32>,
+    /// Whether the router currently blocks the client.
+    pub blocked: bool,
+    /// The manufacturer registered for the MAC's prefix.
+    pub vendor: Option<String>,
+}
+
+/// Combines client lists, e.g. from several routers or sites, keeping the
+/// first client of each MAC.
+pub(crate) fn merge_clients(lists: Vec<Vec<Client>>) -> Vec<Client> {
+    let mut macs = HashSet::new();
+    lists
+        .into_iter()
+        .flatten()
+        .filter(|client| macs.insert(client.mac.clone()))
+        .collect()
+}
+
+/// Lowercases a MAC address and separates it with colons, whatever separators
+/// (colons, dashes or dots) it was written with. Input that isn't made of
+/// 12 hex digits is only trimmed and lowercased.
+pub fn normalize_mac(mac: &str) -> String {
+    let hex: String = mac
+        .trim()
+        .chars()
+        .filter(|c| !matches!(c, ':' | '-' | '.'))
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    if hex.len() != 12 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return mac.trim().to_ascii_lowercase();
+    }
+    let mut normalized = String::with_capacity(17);
+    for (i, c) in hex.chars().enumerate() {
+        if i > 0 && i % 2 == 0 {
+            normalized.push(':');
+        }
+        normalized.push(c);
+    }
+    normalized
+}
+
+/// Returns the normalized MAC if `s` is a MAC address.
+pub fn parse_mac(s: &str) -> Option<String> {
+    let mac = normalize_mac(s);
+    let valid = mac.len() == 17
+        && mac
+            .split(':')
+            .all(|g| g.len() == 2 && g.chars().all(|c| c.is_ascii_hexdigit()));
+    valid.then_some(mac)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_any_notation() {
+        for mac in [
+            "aa:bb:cc:00:00:01",
+            "AA:BB:CC:00:00:01",
+            "aa-bb-cc-00-00-01",
+            "AA-BB-CC-00-00-01",
+            "aabb.cc00.0001",
+            " aabbcc000001 ",
+        ] {
+            assert_eq!(normalize_mac(mac), "aa:bb:cc:00:00:01", "{mac}");
+            assert_eq!(
+                parse_mac(mac).as_deref(),
+                Some("aa:bb:cc:00:00:01"),
+                "{mac}"
+            );
+        }
+    }
+
+    #[test]
+    fn names_are_not_macs() {
+        assert_eq!(normalize_mac(" Alices-iPhone "), "alices-iphone");
+        assert_eq!(parse_mac("Alices-iPhone"), None);
+        assert_eq!(parse_mac("aa:bb:cc:00:00"), None);
+        assert_eq!(parse_mac("aa:bb:cc:00:00:0g"), None);
+    }
 }
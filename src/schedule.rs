@@ -0,0 +1,155 @@
+use chrono::NaiveTime;
+use serde::{de, Deserialize, Deserializer};
+
+use crate::{
+    config::{Config, Device},
+    presence::{device_matches, names_match},
+    router::Client,
+};
+
+/// A daily window of local time written as `"HH:MM"`. A window ending
+/// before it starts runs past midnight, e.g. 21:00 to 07:00.
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct TimeWindow {
+    #[serde(deserialize_with = "deserialize_time")]
+    pub from: NaiveTime,
+    #[serde(deserialize_with = "deserialize_time")]
+    pub to: NaiveTime,
+}
+
+impl TimeWindow {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.from <= self.to {
+            self.from <= time && time < self.to
+        } else {
+            time >= self.from || time < self.to
+        }
+    }
+}
+
+fn deserialize_time<'de, D: Deserializer<'de>>(deserializer: D) -> Result<NaiveTime, D::Error> {
+    let time = String::deserialize(deserializer)?;
+    NaiveTime::parse_from_str(&time, "%H:%M")
+        .map_err(|_| de::Error::custom(format!("invalid time {time:?}, expected HH:MM")))
+}
+
+/// Keeps a client, or all of a person's devices, blocked during a window.
+#[derive(Deserialize, Debug, Clone)]
+pub struct ScheduleEntry {
+    /// A person's name, or a client name or MAC.
+    pub target: String,
+    #[serde(flatten)]
+    pub window: TimeWindow,
+}
+
+impl ScheduleEntry {
+    pub fn applies_to(&self, config: &Config, client: &Client) -> bool {
+        match config
+            .persons
+            .iter()
+            .find(|p| names_match(&p.name, &self.target))
+        {
+            Some(person) => person
+                .devices
+                .iter()
+                .flat_map(Device::names)
+                .any(|d| device_matches(d, client)),
+            None => device_matches(&self.target, client),
+        }
+    }
+}
+
+/// Whether the schedule wants the client blocked at `time`, or `None` if no
+/// entry is about the client.
+pub fn should_block(config: &Config, client: &Client, time: NaiveTime) -> Option<bool> {
+    config
+        .schedule
+        .iter()
+        .filter(|entry| entry.applies_to(config, client))
+        .map(|entry| entry.window.contains(time))
+        .reduce(|a, b| a || b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{client, config};
+
+    fn at(time: &str) -> NaiveTime {
+        NaiveTime::parse_from_str(time, "%H:%M").unwrap()
+    }
+
+    fn window(from: &str, to: &str) -> TimeWindow {
+        TimeWindow {
+            from: at(from),
+            to: at(to),
+        }
+    }
+
+    fn scheduled() -> Config {
+        config(
+            r#"
+            [[persons]]
+            name = "Bob"
+            devices = ["bobs-pixel", "AA-BB-CC-00-00-02"]
+
+            [[schedule]]
+            target = "bob"
+            from = "22:00"
+            to = "07:00"
+
+            [[schedule]]
+            target = "garage-tv"
+            from = "09:00"
+            to = "17:00"
+            "#,
+        )
+    }
+
+    #[test]
+    fn same_day_window_includes_its_start_but_not_its_end() {
+        let work = window("09:00", "17:00");
+        assert!(!work.contains(at("08:59")));
+        assert!(work.contains(at("09:00")));
+        assert!(work.contains(at("12:00")));
+        assert!(!work.contains(at("17:00")));
+        assert!(!work.contains(at("23:00")));
+    }
+
+    #[test]
+    fn window_ending_before_it_starts_runs_past_midnight() {
+        let night = window("22:00", "07:00");
+        assert!(!night.contains(at("21:59")));
+        assert!(night.contains(at("22:00")));
+        assert!(night.contains(at("00:00")));
+        assert!(night.contains(at("06:59")));
+        assert!(!night.contains(at("07:00")));
+        assert!(!night.contains(at("12:00")));
+    }
+
+    #[test]
+    fn person_schedule_applies_to_all_their_devices() {
+        let config = scheduled();
+        for device in [
+            client("bobs-pixel", "aa:bb:cc:00:00:01"),
+            client("unnamed", "aa:bb:cc:00:00:02"),
+        ] {
+            assert_eq!(should_block(&config, &device, at("23:00")), Some(true));
+            assert_eq!(should_block(&config, &device, at("12:00")), Some(false));
+        }
+    }
+
+    #[test]
+    fn client_schedule_applies_to_the_client() {
+        let config = scheduled();
+        let tv = client("Garage-TV", "aa:bb:cc:00:00:03");
+        assert_eq!(should_block(&config, &tv, at("12:00")), Some(true));
+        assert_eq!(should_block(&config, &tv, at("23:00")), Some(false));
+    }
+
+    #[test]
+    fn unscheduled_client_is_left_alone() {
+        let phone = client("Alices-iPhone", "aa:bb:cc:00:00:04");
+        assert_eq!(should_block(&scheduled(), &phone, at("23:00")), None);
+    }
+}
@@ -0,0 +1,81 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::{
+    cache,
+    changes::{changes_by_key, Change},
+    router::Client,
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SeenClient {
+    pub name: String,
+    pub mac: String,
+    /// Unix timestamp of when the client was first seen online.
+    pub first_seen: i64,
+}
+
+/// Every client ever seen online, kept in the cache dir between runs.
+pub struct SeenClients {
+    path: PathBuf,
+    clients: Option<Vec<SeenClient>>,
+}
+
+impl SeenClients {
+    pub fn load() -> Result<Self> {
+        let path = cache::cache_dir()?.join("seen-clients.json");
+        let clients = load(&path);
+        Ok(Self { path, clients })
+    }
+
+    /// Records the online clients, returning the ones never seen before.
+    /// The first time nothing is returned, as everything would be new.
+    pub fn record(&mut self, online: &[Client]) -> Result<Vec<SeenClient>> {
+        let now = chrono::Utc::now().timestamp();
+        let current: Vec<_> = online
+            .iter()
+            .map(|c| SeenClient {
+                name: c.name.clone(),
+                mac: c.mac.clone(),
+                first_seen: now,
+            })
+            .collect();
+        let new: Vec<_> = match &self.clients {
+            Some(seen) => changes_by_key(seen, &current, |c| c.mac.as_str())
+                .into_iter()
+                .filter_map(|change| match change {
+                    Change::Added(client) => Some(client.clone()),
+                    _ => None,
+                })
+                .collect(),
+            None => {
+                self.clients = Some(current);
+                self.save()?;
+                return Ok(Vec::new());
+            }
+        };
+        if !new.is_empty() {
+            self.clients
+                .get_or_insert_with(Vec::new)
+                .extend(new.clone());
+            self.save()?;
+        }
+        Ok(new)
+    }
+
+    fn save(&self) -> Result<()> {
+        std::fs::write(&self.path, serde_json::to_string(&self.clients)?)
+            .with_context(|| format!("Unable to write {}", self.path.display()))
+    }
+}
+
+/// `None` if nothing has been recorded yet.
+fn load(path: &Path) -> Option<Vec<SeenClient>> {
+    let seen = std::fs::read_to_string(path).ok()?;
+    serde_json::from_str(&seen)
+        .map_err(|e| warn!("Ignoring unreadable {}: {e}", path.display()))
+        .ok()
+}
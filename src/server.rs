@@ -0,0 +1,124 @@
+use std::{convert::Infallible, future::Future, net::SocketAddr, sync::Arc};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use hyper::{
+    header::CONTENT_TYPE,
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server, StatusCode,
+};
+use tokio::sync::RwLock;
+use tracing::info;
+
+use crate::presence::{PersonStatus, WhosHome};
+
+/// The latest polled presence, `None` until the first poll succeeds.
+pub type SharedStatuses = Arc<RwLock<Option<Polled>>>;
+
+/// The statuses from the last successful poll.
+pub struct Polled {
+    pub statuses: Vec<PersonStatus>,
+    pub polled_at: DateTime<Utc>,
+    /// Whether polls have failed since, e.g. while the routers reboot.
+    pub stale: bool,
+}
+
+impl Polled {
+    pub fn new(statuses: Vec<PersonStatus>) -> Self {
+        Self {
+            statuses,
+            polled_at: Utc::now(),
+            stale: false,
+        }
+    }
+}
+
+/// Serves the statuses as JSON on `GET /whoshome`, the same as
+/// `show-whos-home --format json`, until `shutdown` completes.
+pub async fn serve(
+    address: SocketAddr,
+    statuses: SharedStatuses,
+    shutdown: impl Future<Output = ()>,
+) -> Result<()> {
+    let make_service = make_service_fn(move |_| {
+        let statuses = statuses.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let statuses = statuses.clone();
+                async move { Ok::<_, Infallible>(handle(&request, &statuses).await) }
+            }))
+        }
+    });
+    info!("Serving presence on http://{address}/whoshome");
+    Server::try_bind(&address)
+        .with_context(|| format!("Unable to listen on {address}"))?
+        .serve(make_service)
+        .with_graceful_shutdown(shutdown)
+        .await
+        .context("HTTP server failed")
+}
+
+async fn handle(request: &Request<Body>, statuses: &SharedStatuses) -> Response<Body> {
+    if request.method() != hyper::Method::GET || request.uri().path() != "/whoshome" {
+        return status_response(StatusCode::NOT_FOUND);
+    }
+    let polled = statuses.read().await;
+    let Some(polled) = polled.as_ref() else {
+        return status_response(StatusCode::SERVICE_UNAVAILABLE);
+    };
+    let whos_home = WhosHome::new(&polled.statuses, polled.stale.then_some(polled.polled_at));
+    match serde_json::to_string(&whos_home) {
+        Ok(json) => Response::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(json))
+            .unwrap_or_else(|_| status_response(StatusCode::INTERNAL_SERVER_ERROR)),
+        Err(_) => status_response(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+/// An empty response with just the given status.
+pub(crate) fn status_response(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(name: &str, home: bool) -> PersonStatus {
+        PersonStatus {
+            name: name.to_string(),
+            home,
+            duration_secs: None,
+            matched_device: None,
+        }
+    }
+
+    async fn get(statuses: &SharedStatuses) -> (StatusCode, serde_json::Value) {
+        let request = Request::get("/whoshome").body(Body::empty()).unwrap();
+        let response = handle(&request, statuses).await;
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or_default())
+    }
+
+    #[tokio::test]
+    async fn serves_the_same_json_as_show_whos_home() {
+        let statuses = SharedStatuses::default();
+        assert_eq!(get(&statuses).await.0, StatusCode::SERVICE_UNAVAILABLE);
+
+        let polled = Polled::new(vec![status("Alice", true), status("Bob", false)]);
+        let expected = serde_json::to_value(WhosHome::new(&polled.statuses, None)).unwrap();
+        *statuses.write().await = Some(polled);
+        let (status, json) = get(&statuses).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(json, expected);
+        assert_eq!((&json["home"], &json["total"]), (&1.into(), &2.into()));
+        assert!(json["stale_as_of"].is_null());
+
+        statuses.write().await.as_mut().unwrap().stale = true;
+        assert!(get(&statuses).await.1["stale_as_of"].is_string());
+    }
+}
@@ -0,0 +1,31 @@
+#[cfg(unix)]
+use std::sync::Once;
+
+/// Tells systemd that the service is up, for running as a `Type=notify`
+/// service. Called after every successful poll, but only the first call
+/// does anything. Does nothing unless systemd set `NOTIFY_SOCKET`.
+pub fn notify_ready() {
+    #[cfg(unix)]
+    {
+        static READY: Once = Once::new();
+        READY.call_once(|| notify(sd_notify::NotifyState::Ready));
+    }
+}
+
+/// Tells the systemd watchdog that the poll loop is still going, whether or
+/// not the poll succeeded, so a router outage that is being waited out
+/// doesn't get the service restarted but a stuck loop does. `WatchdogSec`
+/// must be longer than the poll interval.
+pub fn notify_alive() {
+    #[cfg(unix)]
+    if sd_notify::watchdog_enabled().is_some() {
+        notify(sd_notify::NotifyState::Watchdog);
+    }
+}
+
+#[cfg(unix)]
+fn notify(state: sd_notify::NotifyState) {
+    if let Err(e) = sd_notify::notify(std::slice::from_ref(&state)) {
+        tracing::warn!("Failed to notify systemd of {state}: {e}");
+    }
+}
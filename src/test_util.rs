@@ -0,0 +1,16 @@
+//! Fixtures shared by the unit tests.
+
+use crate::{config::Config, router::Client};
+
+pub(crate) fn client(name: &str, mac: &str) -> Client {
+    Client {
+        name: name.to_string(),
+        mac: mac.to_string(),
+        ..Default::default()
+    }
+}
+
+/// A config for one router with the given persons, or other TOML.
+pub(crate) fn config(persons: &str) -> Config {
+    toml::from_str(&format!("router = \"192.168.1.1\"\n{persons}")).unwrap()
+}
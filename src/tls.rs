@@ -0,0 +1,72 @@
+use std::{sync::Arc, time::SystemTime};
+
+use anyhow::{anyhow, Result};
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier},
+    Certificate, ClientConfig, ServerName,
+};
+use sha2::{Digest, Sha256};
+
+/// Parses a SHA-256 fingerprint written as hex, optionally separated by colons.
+pub fn parse_fingerprint(fingerprint: &str) -> Result<Vec<u8>> {
+    let hex: String = fingerprint
+        .chars()
+        .filter(|c| *c != ':' && !c.is_whitespace())
+        .collect();
+    if hex.len() != 64 {
+        return Err(anyhow!(
+            "Certificate fingerprint must be a SHA-256 hash (64 hex digits), got {fingerprint}"
+        ));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&hex[i..i + 2], 16)
+                .map_err(|_| anyhow!("Invalid hex in certificate fingerprint {fingerprint}"))
+        })
+        .collect()
+}
+
+fn format_fingerprint(fingerprint: &[u8]) -> String {
+    fingerprint
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// A TLS config that trusts exactly the server certificate with the given
+/// SHA-256 fingerprint, regardless of who issued it.
+pub fn pinned_config(fingerprint: Vec<u8>) -> ClientConfig {
+    ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(FingerprintVerifier { fingerprint }))
+        .with_no_client_auth()
+}
+
+struct FingerprintVerifier {
+    fingerprint: Vec<u8>,
+}
+
+impl ServerCertVerifier for FingerprintVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let actual = Sha256::digest(&end_entity.0);
+        if actual.as_slice() == self.fingerprint.as_slice() {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(format!(
+                "Router certificate fingerprint {} does not match the configured {}",
+                format_fingerprint(&actual),
+                format_fingerprint(&self.fingerprint)
+            )))
+        }
+    }
+}
@@ -1,48 +1,99 @@
-use std::{ops::DerefMut, sync::Arc};
+use std::{
+    collections::HashSet,
+    io::Write,
+    ops::DerefMut,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
 use anyhow::{anyhow, Context, Result};
 use async_trait::async_trait;
 use early::Early;
-use netrc_rs::Netrc;
-use reqwest::{header::HeaderMap, RequestBuilder, Response, StatusCode};
+use futures::future::join_all;
+use reqwest::{
+    cookie::{CookieStore, Jar},
+    header::{HeaderMap, RETRY_AFTER},
+    RequestBuilder, Response, StatusCode, Url,
+};
 use serde::{Deserialize, Serialize};
 use tokio::sync::Mutex;
-use tracing::{debug, info, trace};
+use tracing::{debug, info, trace, warn};
 
-use crate::router::Client;
+use crate::{
+    cache,
+    config::Config,
+    credentials::{self, CredentialChain},
+    http,
+    router::{merge_clients, normalize_mac, Client, Router, RouterError},
+    vendor,
+};
+
+const DEFAULT_SITE: &str = "default";
+const DEFAULT_CONTROLLER_PORT: u16 = 8443;
+const PAGE_SIZE: usize = 1000;
+const DEFAULT_RETRIES: u32 = 3;
+const DEFAULT_RETRY_BASE_DELAY_MS: u64 = 500;
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(1);
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(30);
+
+/// The URL layout of the UniFi API.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ApiStyle {
+    /// UniFi OS gateways (UDM, UDM-Pro, UDR) proxying the network app.
+    #[default]
+    Udm,
+    /// A standalone, self-hosted UniFi Network controller.
+    Controller,
+}
 
 pub struct UnifiDreamRouter {
     http_client: reqwest::Client,
     login_url: String,
-    known_devices_url: String,
-    connected_devices_url: String,
-    site_url: String,
+    sites: Vec<Site>,
     hostname: String,
+    username: Option<String>,
     csrf_token: Arc<Mutex<Option<String>>>,
+    credentials: Arc<CredentialChain>,
+    netrc_path: PathBuf,
+    retries: u32,
+    retry_base_delay: Duration,
+    timeout: Duration,
+    cookie_jar: Arc<Jar>,
+    base_url: Url,
+    session_file: Option<PathBuf>,
+    /// What's in `session_file`, so it's only written when that changes.
+    saved_session: std::sync::Mutex<SessionState>,
+    /// The known clients of each site, in the order of `sites`.
+    known_clients: Mutex<Option<Vec<Vec<Client>>>>,
+    /// Counts logins, so concurrent requests can tell if another one
+    /// already logged in again.
+    logins: Mutex<u64>,
 }
 
 #[async_trait]
-impl crate::router::Router for UnifiDreamRouter {
-    async fn known_clients(&self) -> Result<Vec<Client>> {
+impl Router for UnifiDreamRouter {
+    async fn known_clients(&self) -> Result<Vec<Client>, RouterError> {
         info!(
             "Getting list of known clients from UnifiDreamRouter: {}",
             self.hostname
         );
-        self.get_client_list(&self.known_devices_url).await
+        Ok(merge_clients(self.known_clients_by_site().await?))
     }
 
-    async fn online_clients(&self) -> Result<Vec<Client>> {
+    async fn online_clients(&self) -> Result<Vec<Client>, RouterError> {
         info!(
             "Getting list of connected clients from UnifiDreamRouter: {}",
             self.hostname
         );
-        self.get_client_list(dbg!(&self.connected_devices_url))
-            .await
+        let (clients, _) = self.get_from_sites(Site::connected_devices_url).await?;
+        Ok(merge_clients(clients))
     }
 
-    async fn block_client(&self, client: &Client) -> Result<()> {
+    async fn block_client(&self, client: &Client) -> Result<(), RouterError> {
         info!("Blocking {}", client.name);
-        let cmd_url = format!("{}/cmd/stamgr", self.site_url);
+        let cmd_url = self.site_of(client).await.cmd_url();
         let req = self
             .http_client
             .post(cmd_url)
@@ -51,9 +102,9 @@ impl crate::router::Router for UnifiDreamRouter {
         Ok(())
     }
 
-    async fn unblock_client(&self, client: &Client) -> Result<()> {
+    async fn unblock_client(&self, client: &Client) -> Result<(), RouterError> {
         info!("Unblocking {}", client.name);
-        let cmd_url = format!("{}/cmd/stamgr", self.site_url);
+        let cmd_url = self.site_of(client).await.cmd_url();
         let req = self
             .http_client
             .post(cmd_url)
@@ -61,69 +112,180 @@ impl crate::router::Router for UnifiDreamRouter {
         self.send(req).await?;
         Ok(())
     }
+
+    fn username(&self) -> Result<String> {
+        match &self.username {
+            Some(username) => Ok(username.clone()),
+            None => credentials::get_username(&self.netrc_path, &self.hostname)
+                .with_context(|| format!("Failed to get username for {}", self.hostname)),
+        }
+    }
 }
 
 impl UnifiDreamRouter {
-    pub fn new(hostname: &str) -> Result<Self> {
-        let router = Early::new("https", hostname);
-        let login_url = router
-            .clone()
-            .path("api")
-            .path("auth")
-            .path("login")
-            .build();
-        let api = router.path("proxy").path("network").path("api");
-        let site = api.path("s").path("default");
-        let known_devices_url = site.clone().path("rest").path("user").build();
-        let connected_devices_url = site.clone().path("stat").path("sta").build();
-        let http_client = reqwest::Client::builder()
-            .danger_accept_invalid_certs(true)
-            .cookie_store(true)
+    pub fn new(hostname: &str, config: &Config, credentials: Arc<CredentialChain>) -> Result<Self> {
+        let session_file = match cache::cache_dir() {
+            Ok(dir) => Some(dir.join(format!("session-{hostname}.json"))),
+            Err(e) => {
+                warn!("Not persisting router session: {e:#}");
+                None
+            }
+        };
+        Self::connect("https", hostname, config, credentials, session_file)
+    }
+
+    fn connect(
+        scheme: &str,
+        hostname: &str,
+        config: &Config,
+        credentials: Arc<CredentialChain>,
+        session_file: Option<PathBuf>,
+    ) -> Result<Self> {
+        let Endpoints {
+            base_url,
+            login_url,
+            sites,
+        } = Endpoints::new(scheme, hostname, config)?;
+        let cookie_jar = Arc::new(Jar::default());
+        let http_client = http::client_builder(config)?
+            .cookie_provider(cookie_jar.clone())
             .build()
             .context("Failed to build http client")?;
+        let session = session_file
+            .as_deref()
+            .map(load_session)
+            .unwrap_or_default();
+        if let Some(cookies) = &session.cookies {
+            for cookie in cookies.split("; ") {
+                cookie_jar.add_cookie_str(cookie, &base_url);
+            }
+        }
 
         Ok(UnifiDreamRouter {
             login_url,
-            known_devices_url,
-            connected_devices_url,
             http_client,
-            site_url: site.build(),
+            sites,
             hostname: hostname.to_owned(),
-            csrf_token: Arc::new(Mutex::new(None)),
+            username: config.username.clone(),
+            csrf_token: Arc::new(Mutex::new(session.csrf_token.clone())),
+            credentials,
+            netrc_path: credentials::netrc_path(config.netrc.as_deref())?,
+            retries: config.retries.unwrap_or(DEFAULT_RETRIES),
+            retry_base_delay: Duration::from_millis(
+                config
+                    .retry_base_delay_ms
+                    .unwrap_or(DEFAULT_RETRY_BASE_DELAY_MS),
+            ),
+            timeout: http::timeout(config),
+            cookie_jar,
+            base_url,
+            session_file,
+            saved_session: std::sync::Mutex::new(session),
+            known_clients: Mutex::new(None),
+            logins: Mutex::new(0),
         })
     }
 
-    async fn send(&self, request: RequestBuilder) -> Result<Response> {
-        let request = self.add_csrf_header(request).await;
+    /// Forgets the known clients cached by [`Router::known_clients`].
+    pub async fn refresh(&self) {
+        *self.known_clients.lock().await = None;
+    }
+
+    async fn send(&self, request: RequestBuilder) -> Result<Response, RouterError> {
         let backup = request
             .try_clone()
             .ok_or_else(|| anyhow!("Failed to clone request"))?;
+        let logins = *self.logins.lock().await;
+        let request = self.add_csrf_header(request).await;
 
-        let response = match request.send().await?.error_for_status() {
-            Ok(response) => response,
-            Err(e) => {
-
-                if e.status()
-                    .ok_or_else(|| anyhow!("Failed to get status from response"))?
-                    == StatusCode::UNAUTHORIZED
-                {
-                    trace!("Got 401, authenticating on: {}", self.hostname);
-                    self.login().await.context("Failed to login on router")?;
-                    trace!("Authenticating finished sending request again");
-                    backup.send().await?.error_for_status()?
-                } else {
-                    return Err(e.into());
+        let response = match session_checked(self.send_with_retry(request).await?).await? {
+            Some(response) => response,
+            None => {
+                // Concurrent requests all get rejected when the session
+                // expires, but only the first one needs to log in.
+                let mut current_logins = self.logins.lock().await;
+                if *current_logins == logins {
+                    trace!("Session expired, authenticating on: {}", self.hostname);
+                    self.login().await?;
+                    *current_logins += 1;
                 }
+                drop(current_logins);
+                trace!("Authenticating finished sending request again");
+                // Replayed only once, so a router that keeps rejecting the
+                // session fails instead of looping.
+                let backup = self.add_csrf_header(backup).await;
+                self.send_with_retry(backup).await?.error_for_status()?
             }
         };
 
         let token = get_csrf_token(response.headers())?;
         debug!("Got CSRF token: {:?}", token);
-        *self.csrf_token.lock().await = token;
+        *self.csrf_token.lock().await = token.clone();
+        self.save_session(token);
 
         Ok(response)
     }
 
+    fn save_session(&self, csrf_token: Option<String>) {
+        let Some(session_file) = &self.session_file else {
+            return;
+        };
+        let session = SessionState {
+            cookies: self
+                .cookie_jar
+                .cookies(&self.base_url)
+                .and_then(|c| c.to_str().ok().map(str::to_owned)),
+            csrf_token,
+        };
+        let mut saved = self.saved_session.lock().unwrap_or_else(|e| e.into_inner());
+        if *saved == session {
+            return;
+        }
+        match save_session(session_file, &session) {
+            Ok(()) => *saved = session,
+            Err(e) => warn!("Failed to save router session: {e:#}"),
+        }
+    }
+
+    async fn send_with_retry(&self, request: RequestBuilder) -> Result<Response, RouterError> {
+        let url = request
+            .try_clone()
+            .and_then(|r| r.build().ok())
+            .map(|r| r.url().to_string())
+            .unwrap_or_else(|| self.hostname.clone());
+        let mut attempt = 0;
+        let mut rate_limited = false;
+        loop {
+            let this_try = request
+                .try_clone()
+                .ok_or_else(|| anyhow!("Failed to clone request"))?;
+            match this_try.send().await {
+                Ok(response)
+                    if response.status() == StatusCode::TOO_MANY_REQUESTS && !rate_limited =>
+                {
+                    let delay = retry_after(response.headers());
+                    debug!("Rate limited by {}, retrying in {delay:?}", self.hostname);
+                    rate_limited = true;
+                    tokio::time::sleep(delay).await;
+                }
+                Err(e) if (e.is_connect() || e.is_timeout()) && attempt < self.retries => {
+                    let delay = self.retry_base_delay * 2u32.pow(attempt);
+                    attempt += 1;
+                    warn!(
+                        "Request to {} failed ({e}), retrying in {delay:?} ({attempt}/{})",
+                        self.hostname, self.retries
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+                result => {
+                    return result.map_err(|e| {
+                        RouterError::from_request(&self.hostname, &url, self.timeout, e)
+                    })
+                }
+            }
+        }
+    }
+
     async fn add_csrf_header(&self, request: RequestBuilder) -> RequestBuilder {
         let mut csrf_token = self.csrf_token.lock().await;
         match csrf_token.deref_mut() {
@@ -137,43 +299,317 @@ impl UnifiDreamRouter {
         }
     }
 
-    async fn login(&self) -> Result<()> {
-        let password = get_password(&self.hostname)
-            .with_context(|| format!("Failed to get password for {}", self.hostname))?;
+    async fn login(&self) -> Result<(), RouterError> {
+        let (username, password) = self.credentials().map_err(|e| RouterError::AuthFailed {
+            host: self.hostname.clone(),
+            reason: format!("{e:#}"),
+        })?;
         let resp = self
             .http_client
             .post(&self.login_url)
-            .json(&Login {
-                username: "peter@peca.dk".into(),
-                password,
-            })
+            .json(&Login { username, password })
             .send()
             .await
-            .context("Login to router failed")?
-            .error_for_status()
-            .context("Login failed")?;
+            .map_err(|e| {
+                RouterError::from_request(&self.hostname, &self.login_url, self.timeout, e)
+            })?;
+        let resp = match resp.status() {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+                return Err(RouterError::AuthFailed {
+                    host: self.hostname.clone(),
+                    reason: "the username or password was rejected".to_string(),
+                })
+            }
+            _ => resp.error_for_status()?,
+        };
         let token = get_csrf_token(resp.headers())?;
         debug!("Got CSRF token at login: {:?}", token);
-        *self.csrf_token.lock().await = token;
+        *self.csrf_token.lock().await = token.clone();
+        self.save_session(token);
         Ok(())
     }
 
-    async fn get_client_list(&self, url: &str) -> Result<Vec<Client>> {
-        let request = self.http_client.get(url);
-        let resp = self.send(request).await;
-        let client_devices: RouterResponse<UnifiClient> = resp?.error_for_status()?.json().await?;
-        let client_devices = client_devices.data;
+    fn credentials(&self) -> Result<(String, String)> {
+        let username = self.username()?;
+        let password = self
+            .credentials
+            .get_password(&self.hostname, &username)
+            .with_context(|| format!("Failed to get password for {}", self.hostname))?;
+        Ok((username, password))
+    }
+
+    async fn known_clients_by_site(&self) -> Result<Vec<Vec<Client>>, RouterError> {
+        let mut known_clients = self.known_clients.lock().await;
+        if let Some(clients) = known_clients.as_ref() {
+            trace!("Using cached list of known clients");
+            return Ok(clients.clone());
+        }
+        let (clients, complete) = self.get_from_sites(Site::known_devices_url).await?;
+        // Sites that failed are tried again next time
+        if complete {
+            *known_clients = Some(clients.clone());
+        }
+        Ok(clients)
+    }
+
+    /// Gets a client list from each site. A site that fails is logged and
+    /// counted as empty, unless every site fails. Also tells whether all the
+    /// sites answered.
+    async fn get_from_sites(
+        &self,
+        url: fn(&Site) -> String,
+    ) -> Result<(Vec<Vec<Client>>, bool), RouterError> {
+        let urls: Vec<_> = self.sites.iter().map(url).collect();
+        let results = join_all(urls.iter().map(|url| self.get_client_list(url))).await;
+        let mut lists = Vec::new();
+        let mut errors = Vec::new();
+        for (site, result) in self.sites.iter().zip(results) {
+            match result {
+                Ok(clients) => lists.push(clients),
+                Err(e) => {
+                    warn!("Skipping site {} on {}: {e}", site.name, self.hostname);
+                    lists.push(Vec::new());
+                    errors.push(e);
+                }
+            }
+        }
+        if errors.len() == self.sites.len() {
+            return Err(errors.remove(0));
+        }
+        Ok((lists, errors.is_empty()))
+    }
+
+    /// The site the client is known on, or the first one if it isn't known.
+    async fn site_of(&self, client: &Client) -> &Site {
+        if self.sites.len() > 1 {
+            if let Ok(known) = self.known_clients_by_site().await {
+                if let Some(i) = known
+                    .iter()
+                    .position(|clients| clients.iter().any(|c| c.mac == client.mac))
+                {
+                    return &self.sites[i];
+                }
+            }
+        }
+        &self.sites[0]
+    }
+
+    async fn get_client_list(&self, url: &str) -> Result<Vec<Client>, RouterError> {
+        let mut client_devices: Vec<UnifiClient> = Vec::new();
+        let mut macs = HashSet::new();
+        loop {
+            let request = self
+                .http_client
+                .get(url)
+                .query(&[("limit", PAGE_SIZE), ("offset", client_devices.len())]);
+            let resp = self.send(request).await;
+            let page: RouterResponse<UnifiClient> = resp?.error_for_status()?.json().await?;
+            if let Some(Meta {
+                rc: Some(rc), msg, ..
+            }) = &page.meta
+            {
+                if rc == "error" {
+                    return Err(RouterError::Api {
+                        host: self.hostname.clone(),
+                        message: msg.clone().unwrap_or_else(|| "no message".to_string()),
+                    });
+                }
+            }
+            let page_len = page.data.len();
+            let total = page.meta.and_then(|m| m.count);
+            let before = client_devices.len();
+            for client in page.data {
+                if macs.insert(client.mac.clone()) {
+                    client_devices.push(client);
+                }
+            }
+            trace!("Got page of {page_len} clients from {url}, total {total:?}");
+
+            // Stop when the router says we have everything, or, when it doesn't say,
+            // when a page comes back short or with nothing new (offset ignored).
+            let more = match total {
+                Some(total) => client_devices.len() < total,
+                None => page_len == PAGE_SIZE,
+            };
+            if !more || client_devices.len() == before {
+                break;
+            }
+        }
 
         Ok(client_devices
             .into_iter()
             .map(|c| Client {
                 name: c.name(),
-                mac: c.mac,
+                vendor: vendor::vendor(&c.mac),
+                mac: normalize_mac(&c.mac),
+                ip: c.ip,
+                uptime: c.uptime,
+                wired: c.is_wired,
+                last_seen: c.last_seen,
+                signal: c.signal,
+                blocked: c.blocked,
             })
             .collect())
     }
 }
 
+/// Where the API of a router lives.
+struct Endpoints {
+    base_url: Url,
+    login_url: String,
+    sites: Vec<Site>,
+}
+
+impl Endpoints {
+    fn new(scheme: &str, hostname: &str, config: &Config) -> Result<Self> {
+        let mut router = Early::new(scheme, hostname);
+        let port = config.port.or(match config.api_style {
+            ApiStyle::Udm => None,
+            ApiStyle::Controller => Some(DEFAULT_CONTROLLER_PORT),
+        });
+        if let Some(port) = port {
+            router = router.port(port);
+        }
+        let base_url = Url::parse(&router.clone().build()).context("Invalid router address")?;
+        let (login_url, api) = match config.api_style {
+            ApiStyle::Udm => (
+                router
+                    .clone()
+                    .path("api")
+                    .path("auth")
+                    .path("login")
+                    .build(),
+                router.path("proxy").path("network").path("api"),
+            ),
+            ApiStyle::Controller => (
+                router.clone().path("api").path("login").build(),
+                router.path("api"),
+            ),
+        };
+        let site_names = match (&config.site, config.sites.as_slice()) {
+            (site, []) => vec![site.as_deref().unwrap_or(DEFAULT_SITE)],
+            (site, sites) => site.iter().chain(sites).map(String::as_str).collect(),
+        };
+        let sites = site_names
+            .into_iter()
+            .map(|name| Site {
+                name: name.to_string(),
+                url: api.clone().path("s").path(name).build(),
+            })
+            .collect();
+        Ok(Self {
+            base_url,
+            login_url,
+            sites,
+        })
+    }
+}
+
+/// One of the sites hosted by the controller.
+struct Site {
+    name: String,
+    url: String,
+}
+
+impl Site {
+    fn known_devices_url(&self) -> String {
+        format!("{}/rest/user", self.url)
+    }
+
+    fn connected_devices_url(&self) -> String {
+        format!("{}/stat/sta", self.url)
+    }
+
+    fn cmd_url(&self) -> String {
+        format!("{}/cmd/stamgr", self.url)
+    }
+}
+
+/// The parts of a logged in session that are kept between runs.
+#[derive(Serialize, Deserialize, Default, PartialEq, Eq)]
+struct SessionState {
+    cookies: Option<String>,
+    csrf_token: Option<String>,
+}
+
+fn load_session(path: &Path) -> SessionState {
+    let session = match std::fs::read_to_string(path) {
+        Ok(session) => session,
+        Err(_) => return SessionState::default(),
+    };
+    serde_json::from_str(&session).unwrap_or_else(|e| {
+        warn!("Ignoring unreadable session file {}: {e}", path.display());
+        SessionState::default()
+    })
+}
+
+/// How long a rate limited client is asked to wait, in seconds or as a
+/// date, capped so a confused router can't stall us for long.
+fn retry_after(headers: &HeaderMap) -> Duration {
+    let delay = headers
+        .get(RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| match v.trim().parse::<u64>() {
+            Ok(secs) => Some(Duration::from_secs(secs)),
+            Err(_) => chrono::DateTime::parse_from_rfc2822(v)
+                .ok()
+                .and_then(|date| {
+                    (date.with_timezone(&chrono::Utc) - chrono::Utc::now())
+                        .to_std()
+                        .ok()
+                }),
+        })
+        .unwrap_or(DEFAULT_RETRY_AFTER);
+    delay.min(MAX_RETRY_AFTER)
+}
+
+/// Only readable by the user, as the session lets anyone use the router.
+fn save_session(path: &Path, session: &SessionState) -> Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options
+        .open(path)
+        .with_context(|| format!("Unable to open {}", path.display()))?;
+    // The mode only applies to new files, so also fix one that an older
+    // version left readable to others
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+    }
+    file.write_all(serde_json::to_string(session)?.as_bytes())
+        .with_context(|| format!("Unable to write {}", path.display()))
+}
+
+/// `None` if the router rejected the request because the session or its
+/// CSRF token expired, which is a 401, or a 403 saying so in the body.
+async fn session_checked(response: Response) -> Result<Option<Response>, RouterError> {
+    match response.status() {
+        StatusCode::UNAUTHORIZED => Ok(None),
+        StatusCode::FORBIDDEN => {
+            let body = response.text().await?;
+            if is_session_error(&body) {
+                Ok(None)
+            } else {
+                Err(anyhow!("Router refused the request (403 Forbidden): {body}").into())
+            }
+        }
+        _ => Ok(Some(response.error_for_status()?)),
+    }
+}
+
+fn is_session_error(body: &str) -> bool {
+    let body = body.to_ascii_lowercase();
+    ["api.err.nositecontext", "api.err.loginrequired", "csrf"]
+        .iter()
+        .any(|marker| body.contains(marker))
+}
+
 fn get_csrf_token(headers: &HeaderMap) -> Result<Option<String>> {
     let header = match headers.get("x-csrf-token") {
         Some(h) => h,
@@ -189,13 +625,20 @@ fn get_csrf_token(headers: &HeaderMap) -> Result<Option<String>> {
 struct UnifiClient {
     name: Option<String>,
     mac: String,
+    ip: Option<String>,
+    uptime: Option<u64>,
+    is_wired: Option<bool>,
+    last_seen: Option<i64>,
+    signal: Option<i32>,
+    #[serde(default)]
+    blocked: bool,
 }
 
 impl UnifiClient {
     fn name(&self) -> String {
         self.name
             .clone()
-            .unwrap_or_else(|| "<unnamed client>".to_string())
+            .unwrap_or_else(|| vendor::unnamed_client_name(&normalize_mac(&self.mac)))
     }
 }
 
@@ -207,21 +650,16 @@ struct Login {
 
 #[derive(Deserialize, Debug)]
 struct RouterResponse<T> {
+    meta: Option<Meta>,
+    #[serde(default = "Vec::new")]
     data: Vec<T>,
 }
 
-pub fn get_password(machine: &str) -> Result<String> {
-    let home = home::home_dir().ok_or_else(|| anyhow!("Unable to find home dir"))?;
-    let netrc = std::fs::read_to_string(home.join(".netrc")).context("Unable to read .netrc")?;
-    let netrc = Netrc::parse(netrc, false).map_err(|e| anyhow!("unable to parse .netrc: {e}"))?;
-    let password = netrc
-        .machines
-        .into_iter()
-        .find(|m| m.name == Some(machine.into()))
-        .ok_or_else(|| anyhow!("Could not find {machine} in .netrc"))?
-        .password
-        .ok_or_else(|| anyhow!("No password for {machine} in .netrc"))?;
-    Ok(password)
+#[derive(Deserialize, Debug)]
+struct Meta {
+    rc: Option<String>,
+    msg: Option<String>,
+    count: Option<usize>,
 }
 
 #[derive(Serialize)]
@@ -253,3 +691,350 @@ impl UnblockCommand {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex as StdMutex;
+
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::credentials::CredentialSource;
+
+    fn config(extra: &str) -> Config {
+        toml::from_str(&format!("router = \"udr\"\npersons = []\n{extra}")).unwrap()
+    }
+
+    fn site_urls(endpoints: &Endpoints) -> Vec<(&str, &str)> {
+        endpoints
+            .sites
+            .iter()
+            .map(|s| (s.name.as_str(), s.url.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn udm_urls_for_the_default_site() {
+        let endpoints = Endpoints::new("https", "udr", &config("")).unwrap();
+        assert_eq!(endpoints.login_url, "https://udr/api/auth/login");
+        assert_eq!(
+            site_urls(&endpoints),
+            [("default", "https://udr/proxy/network/api/s/default")]
+        );
+        assert_eq!(
+            endpoints.sites[0].known_devices_url(),
+            "https://udr/proxy/network/api/s/default/rest/user"
+        );
+        assert_eq!(
+            endpoints.sites[0].connected_devices_url(),
+            "https://udr/proxy/network/api/s/default/stat/sta"
+        );
+        assert_eq!(
+            endpoints.sites[0].cmd_url(),
+            "https://udr/proxy/network/api/s/default/cmd/stamgr"
+        );
+    }
+
+    #[test]
+    fn custom_site_on_a_controller() {
+        let config = config("site = \"cabin\"\napi_style = \"controller\"");
+        let endpoints = Endpoints::new("https", "udr", &config).unwrap();
+        assert_eq!(endpoints.login_url, "https://udr:8443/api/login");
+        assert_eq!(
+            site_urls(&endpoints),
+            [("cabin", "https://udr:8443/api/s/cabin")]
+        );
+    }
+
+    #[test]
+    fn site_followed_by_the_other_sites() {
+        let config = config("site = \"home\"\nsites = [\"cabin\", \"office\"]\nport = 443");
+        let endpoints = Endpoints::new("https", "udr", &config).unwrap();
+        assert_eq!(
+            site_urls(&endpoints),
+            [
+                ("home", "https://udr:443/proxy/network/api/s/home"),
+                ("cabin", "https://udr:443/proxy/network/api/s/cabin"),
+                ("office", "https://udr:443/proxy/network/api/s/office"),
+            ]
+        );
+    }
+
+    const LOGIN_REQUIRED: &str =
+        r#"{"meta":{"rc":"error","msg":"api.err.LoginRequired"},"data":[]}"#;
+    const CLIENTS_PAGE: &str =
+        r#"{"meta":{"rc":"ok"},"data":[{"mac":"AA:BB:CC:00:00:01","name":"phone"}]}"#;
+
+    #[test]
+    fn session_errors_in_403_bodies() {
+        assert!(is_session_error(LOGIN_REQUIRED));
+        assert!(is_session_error(
+            r#"{"meta":{"rc":"error","msg":"api.err.NoSiteContext"},"data":[]}"#
+        ));
+        assert!(is_session_error(r#"{"errors":["Invalid CSRF Token"]}"#));
+        assert!(!is_session_error(
+            r#"{"meta":{"rc":"error","msg":"api.err.NoPermission"},"data":[]}"#
+        ));
+        assert!(!is_session_error(""));
+    }
+
+    /// A plain HTTP router that accepts any login and answers everything
+    /// else with `responses` in turn, repeating the last one. Returns its
+    /// address and the requests it got, as "METHOD path?query".
+    async fn fake_router(
+        responses: Vec<(u16, &'static str)>,
+    ) -> (String, Arc<StdMutex<Vec<String>>>) {
+        let responses = StdMutex::new(responses);
+        fake_router_with(move |_| {
+            let mut responses = responses.lock().unwrap();
+            let (status, body) = if responses.len() > 1 {
+                responses.remove(0)
+            } else {
+                responses[0]
+            };
+            (status, body.to_string())
+        })
+        .await
+    }
+
+    /// Like [`fake_router`], but answering with `respond` given the
+    /// "path?query" of the request.
+    async fn fake_router_with(
+        respond: impl Fn(&str) -> (u16, String) + Send + Sync + 'static,
+    ) -> (String, Arc<StdMutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let requests = Arc::new(StdMutex::new(Vec::new()));
+        let log = requests.clone();
+        let respond = Arc::new(respond);
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let (log, respond) = (log.clone(), respond.clone());
+                tokio::spawn(async move {
+                    let (read, mut write) = stream.into_split();
+                    let mut read = BufReader::new(read);
+                    loop {
+                        let mut request_line = String::new();
+                        if read.read_line(&mut request_line).await.unwrap_or(0) == 0 {
+                            return;
+                        }
+                        let mut content_length = 0;
+                        loop {
+                            let mut header = String::new();
+                            read.read_line(&mut header).await.unwrap();
+                            if header.trim().is_empty() {
+                                break;
+                            }
+                            if let Some((name, value)) = header.split_once(':') {
+                                if name.eq_ignore_ascii_case("content-length") {
+                                    content_length = value.trim().parse().unwrap();
+                                }
+                            }
+                        }
+                        let mut body = vec![0; content_length];
+                        read.read_exact(&mut body).await.unwrap();
+                        let mut parts = request_line.split_whitespace();
+                        let method = parts.next().unwrap_or_default();
+                        let target = parts.next().unwrap_or_default();
+                        log.lock().unwrap().push(format!("{method} {target}"));
+                        let (status, body) = if target.ends_with("/login") {
+                            (200, "{}".to_string())
+                        } else {
+                            respond(target)
+                        };
+                        let response = format!(
+                            "HTTP/1.1 {status} X\r\ncontent-type: application/json\r\n\
+                             x-csrf-token: token\r\ncontent-length: {}\r\n\r\n{body}",
+                            body.len()
+                        );
+                        write.write_all(response.as_bytes()).await.unwrap();
+                    }
+                });
+            }
+        });
+        (address, requests)
+    }
+
+    fn router(address: &str, session_file: Option<PathBuf>) -> UnifiDreamRouter {
+        router_with(address, "", session_file)
+    }
+
+    fn router_with(address: &str, extra: &str, session_file: Option<PathBuf>) -> UnifiDreamRouter {
+        let config = config(&format!("username = \"admin\"\nretries = 0\n{extra}"));
+        let credentials = Arc::new(CredentialChain::new(
+            &[CredentialSource::Cli],
+            Some("secret".to_string()),
+            PathBuf::new(),
+        ));
+        UnifiDreamRouter::connect("http", address, &config, credentials, session_file).unwrap()
+    }
+
+    const CLIENT_LIST: &str = "GET /proxy/network/api/s/default/stat/sta?limit=1000&offset=0";
+    const LOGIN: &str = "POST /api/auth/login";
+
+    #[tokio::test]
+    async fn logs_in_and_replays_after_a_session_error() {
+        let (address, requests) =
+            fake_router(vec![(403, LOGIN_REQUIRED), (200, CLIENTS_PAGE)]).await;
+        let clients = router(&address, None).online_clients().await.unwrap();
+        assert_eq!(clients.len(), 1);
+        assert_eq!(clients[0].mac, "aa:bb:cc:00:00:01");
+        assert_eq!(*requests.lock().unwrap(), [CLIENT_LIST, LOGIN, CLIENT_LIST]);
+    }
+
+    #[tokio::test]
+    async fn replays_only_once() {
+        let (address, requests) = fake_router(vec![(401, LOGIN_REQUIRED)]).await;
+        assert!(router(&address, None).online_clients().await.is_err());
+        assert_eq!(*requests.lock().unwrap(), [CLIENT_LIST, LOGIN, CLIENT_LIST]);
+    }
+
+    #[tokio::test]
+    async fn other_403s_fail_without_logging_in() {
+        let body = r#"{"meta":{"rc":"error","msg":"api.err.NoPermission"},"data":[]}"#;
+        let (address, requests) = fake_router(vec![(403, body)]).await;
+        assert!(router(&address, None).online_clients().await.is_err());
+        assert_eq!(*requests.lock().unwrap(), [CLIENT_LIST]);
+    }
+
+    #[tokio::test]
+    async fn session_is_saved_privately_and_only_when_it_changes() {
+        let path =
+            std::env::temp_dir().join(format!("whoshome-{}-session.json", std::process::id()));
+        let (address, _) = fake_router(vec![(200, CLIENTS_PAGE)]).await;
+        let router = router(&address, Some(path.clone()));
+        router.online_clients().await.unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        std::fs::remove_file(&path).unwrap();
+        // Same token and no cookies this time
+        router.online_clients().await.unwrap();
+        assert!(!path.exists());
+    }
+
+    /// A page of `len` clients starting at number `first`, with the total
+    /// count if given.
+    fn page(first: usize, len: usize, count: Option<usize>) -> String {
+        let clients: Vec<_> = (first..first + len)
+            .map(|i| format!(r#"{{"mac":"02:00:00:00:{:02x}:{:02x}"}}"#, i / 256, i % 256))
+            .collect();
+        let count = count
+            .map(|c| format!(r#","count":{c}"#))
+            .unwrap_or_default();
+        format!(
+            r#"{{"meta":{{"rc":"ok"{count}}},"data":[{}]}}"#,
+            clients.join(",")
+        )
+    }
+
+    fn offset(target: &str) -> usize {
+        target.rsplit_once("offset=").unwrap().1.parse().unwrap()
+    }
+
+    fn offsets(requests: &StdMutex<Vec<String>>) -> Vec<usize> {
+        requests.lock().unwrap().iter().map(|r| offset(r)).collect()
+    }
+
+    #[tokio::test]
+    async fn pages_until_the_count_is_reached() {
+        let (address, requests) = fake_router_with(|target| {
+            let offset = offset(target);
+            (
+                200,
+                page(offset, (1500 - offset).min(PAGE_SIZE), Some(1500)),
+            )
+        })
+        .await;
+        let clients = router(&address, None).online_clients().await.unwrap();
+        assert_eq!(clients.len(), 1500);
+        assert_eq!(offsets(&requests), [0, 1000]);
+    }
+
+    #[tokio::test]
+    async fn short_page_is_the_last() {
+        let (address, requests) = fake_router_with(|target| match offset(target) {
+            0 => (200, page(0, PAGE_SIZE, None)),
+            offset => (200, page(offset, 3, None)),
+        })
+        .await;
+        let clients = router(&address, None).online_clients().await.unwrap();
+        assert_eq!(clients.len(), 1003);
+        assert_eq!(offsets(&requests), [0, 1000]);
+    }
+
+    #[tokio::test]
+    async fn stops_when_the_offset_is_ignored() {
+        let (address, requests) = fake_router_with(|_| (200, page(0, PAGE_SIZE, Some(5000)))).await;
+        let clients = router(&address, None).online_clients().await.unwrap();
+        assert_eq!(clients.len(), PAGE_SIZE);
+        assert_eq!(offsets(&requests), [0, 1000]);
+    }
+
+    #[tokio::test]
+    async fn failing_site_is_skipped() {
+        let (address, _) = fake_router_with(|target| {
+            if target.contains("/s/cabin/") {
+                (500, String::new())
+            } else {
+                (200, CLIENTS_PAGE.to_string())
+            }
+        })
+        .await;
+        let router = router_with(&address, "site = \"home\"\nsites = [\"cabin\"]", None);
+        let clients = router.online_clients().await.unwrap();
+        assert_eq!(clients.len(), 1);
+        assert_eq!(clients[0].mac, "aa:bb:cc:00:00:01");
+    }
+
+    fn retry_after_header(value: &str) -> Duration {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, value.parse().unwrap());
+        retry_after(&headers)
+    }
+
+    #[test]
+    fn retry_after_in_seconds() {
+        assert_eq!(retry_after_header("5"), Duration::from_secs(5));
+        assert_eq!(retry_after_header(" 0 "), Duration::ZERO);
+    }
+
+    #[test]
+    fn retry_after_as_a_date() {
+        let at = chrono::Utc::now() + chrono::Duration::seconds(10);
+        let delay = retry_after_header(&at.format("%a, %d %b %Y %H:%M:%S GMT").to_string());
+        assert!(
+            delay > Duration::from_secs(8) && delay <= Duration::from_secs(10),
+            "{delay:?}"
+        );
+        // A date in the past means no wait
+        assert_eq!(
+            retry_after_header("Wed, 21 Oct 2015 07:28:00 GMT"),
+            DEFAULT_RETRY_AFTER
+        );
+    }
+
+    #[test]
+    fn retry_after_defaults_when_missing_or_garbled() {
+        assert_eq!(retry_after(&HeaderMap::new()), DEFAULT_RETRY_AFTER);
+        assert_eq!(retry_after_header("soon"), DEFAULT_RETRY_AFTER);
+        assert_eq!(retry_after_header("-3"), DEFAULT_RETRY_AFTER);
+    }
+
+    #[test]
+    fn retry_after_is_capped() {
+        assert_eq!(retry_after_header("3600"), MAX_RETRY_AFTER);
+        let at = chrono::Utc::now() + chrono::Duration::hours(1);
+        assert_eq!(
+            retry_after_header(&at.format("%a, %d %b %Y %H:%M:%S GMT").to_string()),
+            MAX_RETRY_AFTER
+        );
+    }
+}
@@ -0,0 +1,36 @@
+use std::sync::OnceLock;
+
+use mac_oui::Oui;
+use tracing::warn;
+
+/// The manufacturer registered for the MAC's prefix, from the OUI table
+/// bundled with the binary. The table is only parsed the first time.
+pub fn vendor(mac: &str) -> Option<String> {
+    static OUI: OnceLock<Option<Oui>> = OnceLock::new();
+    let oui = OUI
+        .get_or_init(|| {
+            Oui::default()
+                .map_err(|e| warn!("Unable to load OUI table: {e}"))
+                .ok()
+        })
+        .as_ref()?;
+    let entry = oui.lookup_by_mac(mac).ok()??;
+    Some(short_name(&entry.company_name).to_string())
+}
+
+/// Drops the legal suffix, e.g. "Apple, Inc." becomes "Apple".
+fn short_name(company_name: &str) -> &str {
+    company_name
+        .split(',')
+        .next()
+        .unwrap_or(company_name)
+        .trim()
+}
+
+/// What to call a client the router has no name for.
+pub fn unnamed_client_name(mac: &str) -> String {
+    match vendor(mac) {
+        Some(vendor) => format!("<{vendor} device {mac}>"),
+        None => "<unnamed client>".to_string(),
+    }
+}
@@ -0,0 +1,109 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use tracing::warn;
+
+use crate::{changes::Change, config::Config, http, seen::SeenClient};
+
+const ATTEMPTS: u32 = 3;
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Event {
+    Arrived,
+    Left,
+    NewDevice,
+}
+
+#[derive(Serialize, Debug)]
+struct Payload<'a> {
+    person: &'a str,
+    event: Event,
+    timestamp: String,
+}
+
+#[derive(Serialize, Debug)]
+struct DevicePayload<'a> {
+    name: &'a str,
+    mac: &'a str,
+    event: Event,
+    timestamp: String,
+}
+
+/// POSTs arrivals, departures and new devices as JSON to every configured URL.
+pub struct Webhooks {
+    http_client: reqwest::Client,
+    urls: Vec<String>,
+}
+
+impl Webhooks {
+    pub fn new(config: &Config, urls: Vec<String>) -> Result<Self> {
+        let timeout = http::timeout(config);
+        let http_client = reqwest::Client::builder()
+            .timeout(timeout)
+            .connect_timeout(timeout)
+            .build()
+            .context("Failed to build http client")?;
+        Ok(Self { http_client, urls })
+    }
+
+    /// Posts the change to each URL, retrying a couple of times. Failures
+    /// are logged rather than returned so one bad endpoint doesn't stop the
+    /// others.
+    pub async fn send(&self, change: &Change<&&str>) {
+        let (person, event) = match change {
+            Change::Added(person) => (person, Event::Arrived),
+            Change::Removed(person) => (person, Event::Left),
+            Change::Changed { .. } => return,
+        };
+        let payload = Payload {
+            person,
+            event,
+            timestamp: chrono::Utc::now().to_rfc3339(),
+        };
+        self.post_all(&payload).await;
+    }
+
+    /// Posts a client that has never been seen on the network before.
+    pub async fn send_new_device(&self, client: &SeenClient) {
+        let payload = DevicePayload {
+            name: &client.name,
+            mac: &client.mac,
+            event: Event::NewDevice,
+            timestamp: chrono::Utc::now().to_rfc3339(),
+        };
+        self.post_all(&payload).await;
+    }
+
+    async fn post_all(&self, payload: &impl Serialize) {
+        for url in &self.urls {
+            if let Err(e) = self.post(url, payload).await {
+                warn!("Webhook {url} failed: {e:#}");
+            }
+        }
+    }
+
+    async fn post(&self, url: &str, payload: &impl Serialize) -> Result<()> {
+        let mut attempt = 1;
+        loop {
+            let result = self
+                .http_client
+                .post(url)
+                .json(payload)
+                .send()
+                .await
+                .and_then(|r| r.error_for_status());
+            match result {
+                Ok(_) => return Ok(()),
+                Err(e) if attempt < ATTEMPTS => {
+                    warn!("Webhook {url} failed ({e}), retrying in {RETRY_DELAY:?} ({attempt}/{ATTEMPTS})");
+                    attempt += 1;
+                    tokio::time::sleep(RETRY_DELAY).await;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}